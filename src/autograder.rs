@@ -0,0 +1,111 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::bench::PerformanceResults;
+use crate::runner::{FinalScore, RunResults};
+
+/// Top level of a Gradescope results.json
+#[derive(Debug, Serialize)]
+struct GradescopeResults<'a> {
+    score: f32,
+    output: String,
+    tests: Vec<GradescopeTest>,
+    /// Kept by Gradescope but not displayed
+    extra_data: ExtraData<'a>,
+}
+
+#[derive(Debug, Serialize)]
+struct ExtraData<'a> {
+    /// Per-outcome counts and points
+    #[serde(flatten)]
+    score: &'a FinalScore,
+    /// The performance curve along with every test's speedup ratios
+    #[serde(skip_serializing_if = "Option::is_none")]
+    performance: Option<&'a PerformanceResults>,
+}
+
+#[derive(Debug, Serialize)]
+struct GradescopeTest {
+    name: String,
+    score: f32,
+    max_score: f32,
+    status: &'static str,
+    output: String,
+}
+
+/// Writes `results` to `path` in the Gradescope autograder schema
+pub fn write_results(path: &Path, results: &RunResults) -> Result<()> {
+    let mut tests: Vec<_> = results
+        .tests
+        .iter()
+        .map(|test| {
+            let mut output = test.outcome.clone();
+            // Passing tests don't need it and most failures already name the
+            // directive they expected
+            if let Some(directive) = test.directive.as_ref().filter(|_| !test.passed) {
+                if !output.contains(&format!("`{directive}`")) {
+                    output.push_str(&format!("\nexpected: {directive}"));
+                }
+            }
+            if let Some(difference) = &test.differs_from_reference {
+                output.push_str(&format!("\ndiffers from reference: {difference}"));
+            }
+
+            GradescopeTest {
+                name: test.name.clone(),
+                score: test.score,
+                max_score: test.max_score,
+                status: if test.passed { "passed" } else { "failed" },
+                output,
+            }
+        })
+        .collect();
+
+    if let Some(performance) = &results.performance {
+        tests.extend(performance.tests.iter().map(|speedup| {
+            let ratios: Vec<_> = speedup
+                .ratios
+                .iter()
+                .map(|(level, ratio)| format!("{ratio:.2}x vs {level}"))
+                .collect();
+
+            GradescopeTest {
+                name: format!("performance: {}", speedup.name),
+                score: speedup.points,
+                max_score: speedup.max_points,
+                status: if speedup.points >= speedup.max_points {
+                    "passed"
+                } else {
+                    "failed"
+                },
+                output: format!(
+                    "{} (median {:.0} {}, scored against {})",
+                    ratios.join(", "),
+                    speedup.student,
+                    speedup.unit,
+                    performance.policy.against
+                ),
+            }
+        }));
+    }
+
+    let score = &results.score;
+    let gradescope = GradescopeResults {
+        score: score.to_score(),
+        output: score.to_string(),
+        tests,
+        extra_data: ExtraData {
+            score,
+            performance: results.performance.as_ref(),
+        },
+    };
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, serde_json::to_string_pretty(&gradescope)?)
+        .with_context(|| format!("Failed to write autograder results to {path:?}"))
+}
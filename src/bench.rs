@@ -1,16 +1,369 @@
-use std::process::{Command, Output};
+use std::collections::BTreeMap;
+use std::fmt::{self, Write as _};
+use std::io;
+use std::process::Command;
+use std::time::Duration;
 
-use perf_event::{Builder, Counter};
+use serde::Serialize;
 
-pub fn execute_cmd(cmd: &str) -> (u64, Output) {
-    let mut counter = Builder::new()
-        .kind(perf_event::events::Hardware::CPU_CYCLES)
-        .build()
-        .unwrap();
+use crate::process::{output_with_timeout, TimedOutput};
+use crate::scoring::{PerformancePolicy, REFERENCE_LEVELS};
 
-    counter.enable().unwrap();
-    let output = Command::new(cmd).output()?;
-    counter.disable().unwrap();
+/// What the samples of a benchmark count
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Unit {
+    /// User-space CPU cycles from a perf_event hardware counter
+    Cycles,
+    /// User and system CPU time from getrusage, when there is no cycle counter
+    CpuMicros,
+}
+
+impl fmt::Display for Unit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Unit::Cycles => write!(f, "cycles"),
+            Unit::CpuMicros => write!(f, "cpu us"),
+        }
+    }
+}
+
+/// One run of a benchmark, measured both ways so that a test falls back to CPU
+/// time as a whole if the cycle counter is missing for any of its runs
+#[derive(Debug, Clone, Copy)]
+pub struct Sample {
+    /// `None` when perf_event isn't available
+    pub cycles: Option<u64>,
+    pub cpu_micros: u64,
+}
+
+impl Sample {
+    /// The unit all of `samples` can be compared in
+    pub fn common_unit(samples: &[Sample]) -> Unit {
+        if samples.iter().all(|sample| sample.cycles.is_some()) {
+            Unit::Cycles
+        } else {
+            Unit::CpuMicros
+        }
+    }
+
+    pub fn value(&self, unit: Unit) -> u64 {
+        match unit {
+            Unit::Cycles => self.cycles.unwrap_or_default(),
+            Unit::CpuMicros => self.cpu_micros,
+        }
+    }
+}
+
+/// Runs `cmd` under `limit`, returning the user-space CPU cycles and the CPU
+/// time it and anything it forked used, along with its output.
+///
+/// CPU time is measured over every child the grader waits on in the meantime,
+/// so benchmarks have to be run one at a time.
+pub fn execute_cmd(cmd: &mut Command, limit: Duration) -> io::Result<(Sample, TimedOutput)> {
+    let cpu_before = children_cpu_time();
+    let counter = CycleCounter::open();
+    let output = output_with_timeout(cmd, limit)?;
+    let cycles = counter.and_then(|mut counter| counter.read().ok());
+    let cpu_time = children_cpu_time().saturating_sub(cpu_before);
+
+    let sample = Sample {
+        cycles,
+        cpu_micros: cpu_time.as_micros() as u64,
+    };
+    Ok((sample, output))
+}
+
+cfg_if::cfg_if! {
+    if #[cfg(target_os = "linux")] {
+        use std::fs::File;
+        use std::io::Read;
+        use std::os::unix::io::FromRawFd;
+
+        use perf_event_open_sys::bindings::{
+            perf_event_attr, PERF_COUNT_HW_CPU_CYCLES, PERF_FLAG_FD_CLOEXEC, PERF_TYPE_HARDWARE,
+        };
+
+        /// Counts user-space cycles of the next process the calling thread starts.
+        /// The counter only switches on when that process execs, so none of the
+        /// grader's own work around the spawn is counted, and it follows anything
+        /// the process forks.
+        struct CycleCounter(File);
+
+        impl CycleCounter {
+            /// `None` when the hardware counter can't be opened, as in most VMs
+            fn open() -> Option<Self> {
+                let mut attrs = perf_event_attr {
+                    size: std::mem::size_of::<perf_event_attr>() as u32,
+                    type_: PERF_TYPE_HARDWARE,
+                    config: PERF_COUNT_HW_CPU_CYCLES.into(),
+                    ..Default::default()
+                };
+                attrs.set_disabled(1);
+                attrs.set_inherit(1);
+                attrs.set_enable_on_exec(1);
+                attrs.set_exclude_kernel(1);
+                attrs.set_exclude_hv(1);
+
+                // pid 0 and cpu -1 watch the calling thread on any CPU
+                let fd = unsafe {
+                    perf_event_open_sys::perf_event_open(
+                        &mut attrs,
+                        0,
+                        -1,
+                        -1,
+                        PERF_FLAG_FD_CLOEXEC.into(),
+                    )
+                };
+                // SAFETY: a non-negative fd was just opened and nothing else owns it
+                (fd >= 0).then(|| Self(unsafe { File::from_raw_fd(fd) }))
+            }
+
+            /// Cycles counted so far. A child's cycles are only added once it exits.
+            fn read(&mut self) -> io::Result<u64> {
+                let mut count = [0; 8];
+                self.0.read_exact(&mut count)?;
+                Ok(u64::from_ne_bytes(count))
+            }
+        }
+    } else {
+        /// There is no cycle counter outside Linux
+        enum CycleCounter {}
+
+        impl CycleCounter {
+            fn open() -> Option<Self> {
+                None
+            }
+
+            fn read(&mut self) -> io::Result<u64> {
+                match *self {}
+            }
+        }
+    }
+}
+
+/// CPU time used by every child process that has been waited on
+fn children_cpu_time() -> Duration {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    unsafe {
+        libc::getrusage(libc::RUSAGE_CHILDREN, &mut usage);
+    }
+    let time = |t: libc::timeval| {
+        Duration::from_secs(t.tv_sec as u64) + Duration::from_micros(t.tv_usec as u64)
+    };
+    time(usage.ru_utime) + time(usage.ru_stime)
+}
+
+/// How far a benchmark's standard deviation can stray from its median, as a
+/// fraction of the median, before speedups measured with it are flagged as noisy
+pub const NOISY_SPREAD: f64 = 0.1;
+
+/// Shortest median CPU time a slowdown is trusted from. getrusage accounts CPU
+/// time in scheduler ticks of up to 10ms, so shorter runs are mostly rounding.
+pub const MIN_CPU_MICROS: f64 = 100_000.0;
+
+/// The ratio to score for `ratio`, measured between `student` and `reference`.
+/// A slowdown that noisy runs, the spread of the runs or too little CPU time
+/// could account for scores as matching the reference, so the same program
+/// never loses points against itself.
+pub fn scored_ratio(ratio: f64, student: &BenchStats, reference: &BenchStats) -> f64 {
+    let noisy = student.spread() > NOISY_SPREAD || reference.spread() > NOISY_SPREAD;
+    let within_spread = 1.0 - ratio <= student.spread() + reference.spread();
+    let too_short =
+        student.unit == Unit::CpuMicros && student.median.min(reference.median) < MIN_CPU_MICROS;
+
+    if ratio < 1.0 && (noisy || within_spread || too_short) {
+        1.0
+    } else {
+        ratio
+    }
+}
+
+/// Summary of repeated runs of one benchmark
+#[derive(Debug, Clone, Serialize)]
+pub struct BenchStats {
+    pub unit: Unit,
+    pub samples: Vec<u64>,
+    pub median: f64,
+    /// Sample variance, 0 with fewer than two samples
+    pub variance: f64,
+}
+
+impl BenchStats {
+    pub fn new(unit: Unit, samples: Vec<u64>) -> Self {
+        let mut sorted = samples.clone();
+        sorted.sort_unstable();
+        let n = sorted.len();
+        let median = match n {
+            0 => 0.0,
+            _ if n % 2 == 1 => sorted[n / 2] as f64,
+            _ => (sorted[n / 2 - 1] as f64 + sorted[n / 2] as f64) / 2.0,
+        };
+
+        let mean = sorted.iter().map(|&s| s as f64).sum::<f64>() / n.max(1) as f64;
+        let variance = if n < 2 {
+            0.0
+        } else {
+            sorted
+                .iter()
+                .map(|&s| (s as f64 - mean).powi(2))
+                .sum::<f64>()
+                / (n - 1) as f64
+        };
+
+        Self {
+            unit,
+            samples,
+            median,
+            variance,
+        }
+    }
+    /// Standard deviation as a fraction of the median
+    pub fn spread(&self) -> f64 {
+        self.variance.sqrt() / self.median.max(1.0)
+    }
+}
+
+/// A benchmarked test
+#[derive(Debug, Clone, Serialize)]
+pub struct BenchReport {
+    /// See [`Test::name`](crate::selection::Test::name)
+    pub name: String,
+    #[serde(flatten)]
+    pub stats: BenchStats,
+}
+
+/// Renders one row per benchmark with its median and variance
+pub fn format_table(reports: &[BenchReport]) -> String {
+    let width = reports
+        .iter()
+        .map(|report| report.name.len())
+        .chain(["test".len()])
+        .max()
+        .unwrap_or_default();
+
+    let mut table = String::new();
+    let _ = writeln!(
+        table,
+        "{:<width$}  {:>4}  {:>14}  {:>12}  unit",
+        "test", "runs", "median", "variance"
+    );
+    for report in reports {
+        let stats = &report.stats;
+        let _ = writeln!(
+            table,
+            "{:<width$}  {:>4}  {:>14.0}  {:>12.3e}  {}",
+            report.name,
+            stats.samples.len(),
+            stats.median,
+            stats.variance,
+            stats.unit
+        );
+    }
+    table
+}
+
+/// How a student's program compares to the reference compiler's
+#[derive(Debug, Clone, Serialize)]
+pub struct Speedup {
+    /// See [`Test::name`](crate::selection::Test::name)
+    pub name: String,
+    pub unit: Unit,
+    /// Median of the student's program
+    pub student: f64,
+    /// Median of the reference compiler's program at each optimization level
+    pub reference: BTreeMap<String, f64>,
+    /// Reference median over student median at each level, above 1 when the
+    /// student's program is faster
+    pub ratios: BTreeMap<String, f64>,
+    /// Points for the ratio against the policy's level, see [`scored_ratio`]
+    pub points: f32,
+    pub max_points: f32,
+    /// Whether the runs varied more than `NOISY_SPREAD`, making the ratio unreliable
+    pub noisy: bool,
+}
+
+/// Performance grading of a --bench run against the reference compiler
+#[derive(Debug, Clone, Serialize)]
+pub struct PerformanceResults {
+    /// The curve the ratios were scored with
+    pub policy: PerformancePolicy,
+    pub tests: Vec<Speedup>,
+    pub points: f32,
+}
+
+/// Renders one row per test with its speedup at each reference level
+pub fn format_speedups(results: &PerformanceResults) -> String {
+    let width = results
+        .tests
+        .iter()
+        .map(|speedup| speedup.name.len())
+        .chain(["test".len()])
+        .max()
+        .unwrap_or_default();
+    let mut table = String::new();
+    let _ = write!(table, "{:<width$}  {:>14}", "test", "student");
+    for level in REFERENCE_LEVELS {
+        let _ = write!(table, "  {:>14}  {:>8}", level, format!("vs {level}"));
+    }
+    let _ = writeln!(table, "  {:>6}", "points");
+
+    for speedup in &results.tests {
+        let _ = write!(table, "{:<width$}  {:>14.0}", speedup.name, speedup.student);
+        for &level in REFERENCE_LEVELS {
+            let _ = write!(
+                table,
+                "  {:>14.0}  {:>7.2}x",
+                speedup.reference[level], speedup.ratios[level]
+            );
+        }
+        let _ = writeln!(table, "  {:>6.2}", speedup.points);
+    }
+    let _ = writeln!(
+        table,
+        "performance: {:.2} points, scored against {}",
+        results.points, results.policy.against
+    );
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn median_and_variance() {
+        let odd = BenchStats::new(Unit::Cycles, vec![5, 1, 3]);
+        assert_eq!(odd.median, 3.0);
+        assert_eq!(odd.variance, 4.0);
+        assert_eq!(odd.spread(), 2.0 / 3.0);
+
+        let even = BenchStats::new(Unit::Cycles, vec![4, 1, 3, 2]);
+        assert_eq!(even.median, 2.5);
+
+        let single = BenchStats::new(Unit::CpuMicros, vec![7]);
+        assert_eq!((single.median, single.variance), (7.0, 0.0));
+    }
+
+    #[test]
+    fn slowdowns_within_the_noise() {
+        let steady = |median: u64| BenchStats::new(Unit::Cycles, vec![median; 5]);
+        let noisy = BenchStats::new(Unit::Cycles, vec![800, 1000, 1200]);
+        assert!(noisy.spread() > NOISY_SPREAD);
+
+        // Steady runs are scored as measured, in both directions
+        assert_eq!(scored_ratio(0.5, &steady(2000), &steady(1000)), 0.5);
+        assert_eq!(scored_ratio(2.0, &steady(500), &steady(1000)), 2.0);
+        // Noisy runs can't lose points, but can still earn them
+        assert_eq!(scored_ratio(0.87, &noisy, &steady(870)), 1.0);
+        assert_eq!(scored_ratio(1.5, &noisy, &steady(1500)), 1.5);
+        // Within the combined spread of the runs
+        let spread = BenchStats::new(Unit::Cycles, vec![950, 1000, 1050]);
+        assert_eq!(scored_ratio(0.96, &spread, &steady(960)), 1.0);
 
-    (counter.read().unwrap(), output)
+        // CPU time too short to be measured reliably
+        let cpu = |median: u64| BenchStats::new(Unit::CpuMicros, vec![median; 5]);
+        assert_eq!(scored_ratio(0.87, &cpu(4000), &cpu(3480)), 1.0);
+        assert_eq!(scored_ratio(0.5, &cpu(400_000), &cpu(200_000)), 0.5);
+    }
 }
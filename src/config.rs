@@ -1,5 +1,6 @@
 use std::path::PathBuf;
 
+use anyhow::{bail, Result};
 use clap::Parser;
 
 // TODO: get rid of unused options
@@ -27,7 +28,7 @@ pub struct Cli {
     #[arg(short = 'm', long)]
     pub make: Option<String>,
 
-    /// Path to reference compiler
+    /// Path to reference compiler, invoked like bin/c0c, for differential testing
     #[arg(long)]
     pub cc0: Option<String>,
 
@@ -43,6 +44,11 @@ pub struct Cli {
     #[arg(short = 'e', long, value_parser = ["x86-64", "exe", "llvm"], default_value = "x86-64")]
     pub emit: String,
 
+    /// Run the student compiler and test programs in Linux namespaces with a
+    /// read-only filesystem and no network (off, auto, required)
+    #[arg(long, value_parser = ["off", "auto", "required"], default_value = "off")]
+    pub sandbox: String,
+
     /// Whether to run only those tests given in keep.txt
     #[arg(long)]
     pub prune: bool,
@@ -56,19 +62,19 @@ pub struct Cli {
     pub static_analysis_dir: Option<String>,
 
     /// Whether to fail duplicate tests. (NOTE: requires static-analysis-dir)
-    #[arg(long)]
+    #[arg(long, requires = "static_analysis_dir")]
     pub fail_duplicate_tests: bool,
 
     /// Whether to print a warning message on duplicate tests. (NOTE: requires static-analysis-dir)
-    #[arg(long)]
+    #[arg(long, requires = "static_analysis_dir")]
     pub warn_duplicate_tests: bool,
 
     /// Whether to fail buggy tests. (NOTE: requires static-analysis-dir)
-    #[arg(long)]
+    #[arg(long, requires = "static_analysis_dir")]
     pub fail_dodgy_tests: bool,
 
     /// Whether to run only unsafe (i.e., mem-error, div-by-zero) tests
-    #[arg(long)]
+    #[arg(long, conflicts_with = "safe_only")]
     pub unsafe_only: bool,
 
     /// Whether to run only safe (i.e., returning, typecheck) tests
@@ -79,6 +85,20 @@ pub struct Cli {
     #[arg(long)]
     pub typecheck_only: bool,
 
+    /// Flag passed to the compiler in --typecheck-only mode
+    #[arg(long, default_value = "-t", allow_hyphen_values = true)]
+    pub typecheck_flag: String,
+
+    /// Benchmark every passing `//test return` test after grading, reporting
+    /// the median and variance of its cycle counts. With --cc0 the speedup over
+    /// the reference compiler at -O0 and -O1 is scored too (see --scoring).
+    #[arg(long, conflicts_with = "typecheck_only")]
+    pub bench: bool,
+
+    /// Times each test is run with --bench (5)
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..), default_value = "5")]
+    pub bench_runs: u32,
+
     /// If present, allow infloop tests.
     #[arg(long)]
     pub allow_infloop_tests: bool,
@@ -103,11 +123,33 @@ pub struct Cli {
     #[arg(long, value_parser = clap::value_parser!(u32), default_value = "5")]
     pub limit_run: u32,
 
+    /// Address space limit for test programs in MiB, 0 for none (4096 MiB)
+    #[arg(long, value_parser = clap::value_parser!(u64), default_value = "4096")]
+    pub limit_memory: u64,
+
+    /// Stack size limit for test programs in MiB, 0 for none (64 MiB)
+    #[arg(long, value_parser = clap::value_parser!(u64), default_value = "64")]
+    pub limit_stack: u64,
+
+    /// CPU time limit for test programs in seconds, 0 for none (--limit-run)
+    #[arg(long, value_parser = clap::value_parser!(u32))]
+    pub limit_cpu: Option<u32>,
+
+    /// Size limit for files written by test programs in MiB, 0 for none (16 MiB)
+    #[arg(long, value_parser = clap::value_parser!(u64), default_value = "16")]
+    pub limit_file_size: u64,
+
+    /// Process limit for the user while a test program runs, 0 for none. The
+    /// default of 1 keeps test programs from forking unless grading as root. (1)
+    #[arg(long, value_parser = clap::value_parser!(u64), default_value = "1")]
+    pub limit_processes: u64,
+
     /// Max length of a filename (37 chars)
     #[arg(long, value_parser = clap::value_parser!(u32), default_value = "37")]
     pub limit_filename: u32,
 
-    /// Relaxed test case validation
+    /// Relaxed test case validation: ignore blank lines before the test
+    /// header and anything after its directive
     #[arg(long)]
     pub relax: bool,
 
@@ -119,7 +161,7 @@ pub struct Cli {
     #[arg(long)]
     pub debug: bool,
 
-    /// Only test specific extension
+    /// Only test specific extension (l1 through l6, c0)
     #[arg(short = 'f', long)]
     pub filter: Option<String>,
 
@@ -139,10 +181,84 @@ pub struct Cli {
     #[arg(long)]
     pub backward_may: bool,
 
+    /// JSON file with the scoring policy (outcome/directive weights, lab caps)
+    #[arg(long)]
+    pub scoring: Option<PathBuf>,
+
     /// Produce autograder output
     #[arg(long)]
     pub autograder: bool,
 
+    /// Where --autograder writes the Gradescope results.json
+    #[arg(long, default_value = "/autograder/results/results.json")]
+    pub autograder_results: PathBuf,
+
     /// Path to test directory
     pub path: PathBuf,
 }
+
+impl Cli {
+    /// Splits `--args` into the individual flags passed to the student compiler
+    pub fn compiler_args(&self) -> Result<Vec<String>> {
+        let Some(args) = &self.args else {
+            return Ok(Vec::new());
+        };
+
+        args.split(',')
+            .map(|arg| {
+                let arg = arg.trim();
+                if arg.is_empty() {
+                    bail!("Empty compiler argument in --args {args:?}");
+                }
+                if !arg.starts_with('-') {
+                    bail!("Compiler argument {arg:?} must start with '-'");
+                }
+                if arg.chars().any(char::is_whitespace) {
+                    bail!("Compiler argument {arg:?} must not contain whitespace");
+                }
+                if arg.starts_with("-e") || arg.starts_with("--emit") {
+                    bail!("Compiler argument {arg:?} conflicts with --emit");
+                }
+                Ok(arg.to_string())
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compiler_args(args: &str) -> Result<Vec<String>> {
+        Cli::parse_from(["grader", &format!("--args={args}"), "tests"]).compiler_args()
+    }
+
+    #[test]
+    fn compiler_args_are_split_and_trimmed() {
+        assert_eq!(
+            compiler_args(" -O1 , --unsafe").unwrap(),
+            vec!["-O1", "--unsafe"]
+        );
+        assert!(Cli::parse_from(["grader", "tests"])
+            .compiler_args()
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn invalid_compiler_args() {
+        for args in [
+            "",
+            "-O1,,--unsafe",
+            "-O1,",
+            "O1",
+            "-O1,unsafe",
+            "-l\tfoo",
+            "-e",
+            "-ex86-64",
+            "--emit=llvm",
+        ] {
+            assert!(compiler_args(args).is_err(), "{args:?}");
+        }
+    }
+}
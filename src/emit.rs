@@ -0,0 +1,121 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+
+use anyhow::{bail, Error};
+
+/// The kinds of output the student compiler can be asked to produce with `-e`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmitMode {
+    /// `<test>.s` assembly linked against run411.c with gcc
+    X86_64,
+    /// An executable produced by the compiler itself
+    Exe,
+    /// `<test>.ll` lowered with llc then linked against run411.c
+    Llvm,
+}
+
+impl FromStr for EmitMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "x86-64" => Ok(EmitMode::X86_64),
+            "exe" => Ok(EmitMode::Exe),
+            "llvm" => Ok(EmitMode::Llvm),
+            other => bail!("Expected emit mode x86-64 | exe | llvm instead got: {other}"),
+        }
+    }
+}
+
+impl EmitMode {
+    /// The flag passed to bin/c0c selecting this mode
+    pub fn compiler_flag(&self) -> &'static str {
+        match self {
+            EmitMode::X86_64 => "-ex86-64",
+            EmitMode::Exe => "-eexe",
+            EmitMode::Llvm => "-ellvm",
+        }
+    }
+
+    /// The file the compiler writes next to `test` in this mode
+    pub fn artifact_path(&self, test: &Path) -> PathBuf {
+        match self {
+            EmitMode::X86_64 => add_extension(test, "s"),
+            EmitMode::Exe => add_extension(test, "exe"),
+            EmitMode::Llvm => add_extension(test, "ll"),
+        }
+    }
+
+    /// The executable that is run once every link step has succeeded
+    pub fn executable_path(&self, artifact: &Path, out: &Path) -> PathBuf {
+        match self {
+            EmitMode::Exe => artifact.to_path_buf(),
+            EmitMode::X86_64 | EmitMode::Llvm => out.to_path_buf(),
+        }
+    }
+
+    /// Whether the artifact has to be linked against run411.c
+    pub fn links_runtime(&self) -> bool {
+        !matches!(self, EmitMode::Exe)
+    }
+
+    /// Commands, run in order, that turn `artifact` into an executable at `out`
+    pub fn link_steps(&self, artifact: &Path, runtime: &Path, out: &Path) -> Vec<Command> {
+        match self {
+            EmitMode::X86_64 => vec![gcc_link(artifact, runtime, out)],
+            EmitMode::Exe => Vec::new(),
+            EmitMode::Llvm => {
+                let asm = add_extension(artifact, "s");
+                let mut llc = Command::new("llc");
+                llc.args(["-O0", "-o"]).arg(&asm).arg(artifact);
+                vec![llc, gcc_link(&asm, runtime, out)]
+            }
+        }
+    }
+}
+
+/// Links an assembly file against the course runtime
+fn gcc_link(asm: &Path, runtime: &Path, out: &Path) -> Command {
+    let mut gcc = Command::new("gcc");
+    gcc.args([
+        "-g",
+        "-fno-stack-protector",
+        "-fno-lto",
+        "-fno-asynchronous-unwind-tables",
+        "-O0",
+    ]);
+    // gcc on macOS is clang, which takes a target triple. GNU gcc rejects
+    // `-target`, so just ask it for 64-bit code.
+    cfg_if::cfg_if! {
+        if #[cfg(target_os = "macos")] {
+            gcc.args(["-target", "x86_64-apple-darwin"]);
+        } else if #[cfg(target_os = "linux")] {
+            gcc.arg("-m64");
+        } else {
+            compile_error!("unspported target os")
+        }
+    }
+    gcc.arg("-o")
+        .arg(out)
+        .arg(asm)
+        .arg(runtime.join("run411.c"));
+    gcc
+}
+
+pub(crate) fn add_extension(path: &Path, extension: impl AsRef<Path>) -> PathBuf {
+    let mut path = path.to_path_buf();
+    match path.extension() {
+        Some(ext) => {
+            let mut ext = ext.to_os_string();
+            ext.push(".");
+            ext.push(extension.as_ref());
+            path.set_extension(ext);
+            path
+        }
+        None => {
+            path.set_extension(extension.as_ref());
+            path
+        }
+    }
+}
@@ -0,0 +1,128 @@
+use std::collections::BTreeSet;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::os::unix::io::FromRawFd;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::{ptr, thread};
+
+use anyhow::{Context, Result};
+
+/// Subprocess groups and tempdirs that have to be cleaned up if the grader is
+/// interrupted
+struct InFlight {
+    groups: BTreeSet<i32>,
+    dirs: BTreeSet<PathBuf>,
+}
+
+static IN_FLIGHT: Mutex<InFlight> = Mutex::new(InFlight {
+    groups: BTreeSet::new(),
+    dirs: BTreeSet::new(),
+});
+
+/// Write end of the pipe the SIGINT handler wakes the cleanup thread through
+static INTERRUPT_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+fn in_flight() -> MutexGuard<'static, InFlight> {
+    IN_FLIGHT.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Spawns `cmd` as the leader of a new process group, so that it and anything
+/// it forks can be killed together. The group is tracked until `untrack_group`.
+pub fn spawn_in_group(cmd: &mut Command) -> io::Result<Child> {
+    // Holding the lock across the spawn means an interrupt can't slip in between
+    // the child starting and it being tracked
+    let mut in_flight = in_flight();
+    let child = cmd.process_group(0).spawn()?;
+    in_flight.groups.insert(child.id() as i32);
+    Ok(child)
+}
+
+/// Stops tracking the group led by `pid` once it has been waited on
+pub fn untrack_group(pid: u32) {
+    in_flight().groups.remove(&(pid as i32));
+}
+
+/// Kills every process in the group led by `pid`
+pub fn kill_group(pid: u32) {
+    unsafe {
+        libc::kill(-(pid as i32), libc::SIGKILL);
+    }
+}
+
+/// Removes `dir` if the grader is interrupted before `untrack_dir`
+pub fn track_dir(dir: &Path) {
+    in_flight().dirs.insert(dir.to_path_buf());
+}
+
+pub fn untrack_dir(dir: &Path) {
+    in_flight().dirs.remove(dir);
+}
+
+extern "C" fn on_interrupt(_: libc::c_int) {
+    // Only async-signal-safe calls are allowed here, so hand off to the cleanup thread
+    let fd = INTERRUPT_PIPE.load(Ordering::Relaxed);
+    unsafe {
+        libc::write(fd, [0u8].as_ptr().cast(), 1);
+    }
+}
+
+/// Installs a SIGINT handler that kills every running subprocess group and
+/// removes every tracked tempdir before exiting.
+///
+/// Subprocesses run in their own process groups, so a Ctrl-C at the terminal
+/// only reaches the grader and it has to pass it on.
+pub fn install_handler() -> Result<()> {
+    let mut fds = [0; 2];
+    unsafe {
+        if libc::pipe(fds.as_mut_ptr()) != 0 {
+            return Err(io::Error::last_os_error()).context("Failed to create interrupt pipe");
+        }
+        for fd in fds {
+            libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        }
+    }
+    INTERRUPT_PIPE.store(fds[1], Ordering::Relaxed);
+
+    // SAFETY: the read end was just created and nothing else owns it
+    let mut interrupts = unsafe { File::from_raw_fd(fds[0]) };
+    thread::spawn(move || {
+        let mut byte = [0];
+        if interrupts.read_exact(&mut byte).is_ok() {
+            clean_up_and_exit();
+        }
+    });
+
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = on_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        if libc::sigaction(libc::SIGINT, &action, ptr::null_mut()) != 0 {
+            return Err(io::Error::last_os_error()).context("Failed to install SIGINT handler");
+        }
+    }
+
+    Ok(())
+}
+
+fn clean_up_and_exit() -> ! {
+    // Keep holding the lock so no new subprocess can start
+    let in_flight = in_flight();
+    for &group in &in_flight.groups {
+        kill_group(group as u32);
+    }
+    for dir in &in_flight.dirs {
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    eprintln!(
+        "\nInterrupted: killed {} running process groups and removed {} test directories",
+        in_flight.groups.len(),
+        in_flight.dirs.len()
+    );
+    std::process::exit(130);
+}
@@ -1,18 +1,30 @@
 use runner::make_and_run;
 
+pub mod autograder;
+pub mod bench;
 pub mod config;
+pub mod emit;
+mod interrupt;
 pub mod parser;
+#[allow(dead_code)]
 mod pipeline;
+mod process;
 pub mod runner;
 pub mod runner_file_utils;
+pub mod sandbox;
+pub mod scoring;
+pub mod selection;
+pub mod test_log;
+pub mod vetting;
 
 pub fn run(cli: config::Cli) -> anyhow::Result<()> {
-    let s = make_and_run(cli.path.clone(), &cli)?;
+    let results = make_and_run(cli.path.clone(), &cli)?;
 
-    println!("Score: {}", s.to_score());
+    println!("Score: {}", results.score.to_score());
 
     if cli.autograder {
-        println!("{}", serde_json::to_string(&s).unwrap());
+        autograder::write_results(&cli.autograder_results, &results)?;
+        println!("Autograder results written to {:?}", cli.autograder_results);
     }
 
     Ok(())
@@ -1,5 +1,5 @@
 use clap::Parser;
-use new_grader::{config::Cli, run, runner::make_and_run};
+use new_grader::{config::Cli, run};
 
 extern crate tempdir;
 
@@ -1,9 +1,10 @@
 use core::str;
+use std::fmt;
 use std::fs::File;
-use std::io::{self, BufRead, BufReader, Error, Write};
+use std::io::{self, BufRead, BufReader};
 use std::path::Path;
 
-use anyhow::{bail, Result};
+use thiserror::Error;
 
 // There are the following test directives according to the L3 writeup
 // test return i program must execute correctly and return i
@@ -13,7 +14,8 @@ use anyhow::{bail, Result};
 // test error program must fail to compile due to an L3 source error
 // test typecheck program must typecheck correctly (see below)
 // test compile
-#[derive(Debug, PartialEq)]
+// test infloop program must compile and run but never terminate
+#[derive(Debug, Clone, PartialEq)]
 pub enum TestResult {
     Ret(i32),
     DivByZero,
@@ -22,68 +24,361 @@ pub enum TestResult {
     SourceError, // TODO: I think this is just general parser error
     TypeCheck,
     Compile,
+    InfLoop,
 }
 
-pub fn get_test_result<P>(p: P) -> Result<TestResult>
+impl TestResult {
+    /// The directive as written after `//test`, without any return value
+    pub fn keyword(&self) -> &'static str {
+        match self {
+            TestResult::Ret(_) => "return",
+            TestResult::DivByZero => "div-by-zero",
+            TestResult::Abort => "abort",
+            TestResult::MemError => "memerror",
+            TestResult::SourceError => "error",
+            TestResult::TypeCheck => "typecheck",
+            TestResult::Compile => "compile",
+            TestResult::InfLoop => "infloop",
+        }
+    }
+
+    /// Whether the test expects a runtime safety check to fire
+    pub fn is_unsafe(&self) -> bool {
+        matches!(
+            self,
+            TestResult::DivByZero | TestResult::Abort | TestResult::MemError
+        )
+    }
+}
+
+impl fmt::Display for TestResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TestResult::Ret(ret) => write!(f, "return {ret}"),
+            other => write!(f, "{}", other.keyword()),
+        }
+    }
+}
+
+/// Why a test's directive couldn't be read
+#[derive(Error, Debug)]
+pub enum ParseError {
+    #[error("Failed to read test: {0}")]
+    Io(#[from] io::Error),
+    #[error("Test file is empty")]
+    EmptyFile,
+    #[error("Expected test directive instead got: {0}")]
+    MissingDirective(String),
+    #[error("{line}:{column}: {message}")]
+    InvalidDirective {
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    #[error("No directive applies when compiling with `{0}`")]
+    NoMatchingDirective(String),
+}
+
+/// One `//test` line: a directive and the compiler flags it is conditional on
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expectation {
+    /// Every flag must be among the compiler args for the directive to apply.
+    /// Empty for an unconditional directive.
+    pub flags: Vec<String>,
+    pub result: TestResult,
+}
+
+/// Every expectation listed at the top of a test
+#[derive(Debug, Clone, PartialEq)]
+pub struct TestHeader {
+    pub expectations: Vec<Expectation>,
+}
+
+impl TestHeader {
+    /// Picks the directive for a compiler run with `args`: the matching
+    /// expectation with the most flags, the earliest one on a tie
+    pub fn expected_for(&self, args: &[String]) -> Option<&TestResult> {
+        self.expectations
+            .iter()
+            .rev()
+            .filter(|expectation| expectation.flags.iter().all(|flag| args.contains(flag)))
+            .max_by_key(|expectation| expectation.flags.len())
+            .map(|expectation| &expectation.result)
+    }
+}
+
+/// Reads the directive that applies to compiling the test at `p` with `args`.
+///
+/// With `relax`, blank lines before the header and anything after the
+/// directive are ignored, so `//test return 3;` is read as `return 3`.
+pub fn get_test_result<P>(p: P, relax: bool, args: &[String]) -> Result<TestResult, ParseError>
 where
     P: AsRef<Path>,
 {
     let file = File::open(p)?;
-    let mut reader = BufReader::new(file);
-    let first_line = get_line(&mut reader)?;
+    let header = read_header(BufReader::new(file), relax)?;
 
-    parse_line(&first_line)
+    header
+        .expected_for(args)
+        .cloned()
+        .ok_or_else(|| ParseError::NoMatchingDirective(args.join(" ")))
 }
 
-fn get_line<R>(mut handle: R) -> Result<String, io::Error>
+/// Reads the first `//test` line and the conditional `//test <flags>:` lines
+/// that directly follow it, e.g.
+///
+/// ```text
+/// //test return 0
+/// //test --unsafe: return 5
+/// ```
+fn read_header<R>(mut handle: R, relax: bool) -> Result<TestHeader, ParseError>
 where
     R: BufRead,
 {
-    let mut input = String::new();
+    let (mut line_number, first) = get_line(&mut handle, relax)?;
+    let mut expectations = vec![parse_line(&first, line_number, relax)?];
+
+    loop {
+        let mut input = String::new();
+        if 0 == handle.read_line(&mut input)? {
+            break;
+        }
+        line_number += 1;
+
+        // Only conditional lines continue the header, so an ordinary
+        // `// test ...` comment further down is left alone
+        let mut header = Header::new(&input, line_number);
+        if !(header.prefix() && header.skip_whitespace() && header.is_condition()) {
+            break;
+        }
 
-    // TODO: handle empty files
-    if 0 == handle.read_line(&mut input)? {
-        std::process::exit(0);
+        let expectation = parse_line(&input, line_number, relax)?;
+        if expectations
+            .iter()
+            .any(|seen| seen.flags == expectation.flags)
+        {
+            return Err(ParseError::InvalidDirective {
+                line: line_number,
+                column: 1,
+                message: format!(
+                    "a directive for `{}` was already given",
+                    expectation.flags.join(" ")
+                ),
+            });
+        }
+        expectations.push(expectation);
     }
 
-    Ok(input)
+    Ok(TestHeader { expectations })
 }
 
-fn parse_line(first_line: &str) -> Result<TestResult> {
-    let words: Vec<_> = first_line.split_whitespace().collect();
+/// Returns the header line along with its 1-based line number
+fn get_line<R>(mut handle: R, skip_blank: bool) -> Result<(usize, String), ParseError>
+where
+    R: BufRead,
+{
+    let mut line_number = 0;
+    loop {
+        let mut input = String::new();
+        if 0 == handle.read_line(&mut input)? {
+            return Err(if line_number == 0 {
+                ParseError::EmptyFile
+            } else {
+                ParseError::MissingDirective(String::new())
+            });
+        }
+        line_number += 1;
 
-    if words.len() < 2 {
-        bail!("Expected test directive instead got: {first_line}")
+        if !(skip_blank && input.trim().is_empty()) {
+            return Ok((line_number, input));
+        }
     }
+}
+
+const DIRECTIVES: &str =
+    "return | div-by-zero | abort | memerror | error | typecheck | compile | infloop";
 
-    if words[0] != "//test" {
-        bail!("Expected test directive to begin with //test instead got: {first_line}")
+/// Parses a test header line:
+///
+/// ```text
+/// header    ::= ws* "//" ws* "test" ws+ condition? directive ws* comment?
+/// condition ::= ("-" flag ws*)+ ":" ws*
+/// directive ::= "return" ws+ ("+" | "-")? digit+ | "div-by-zero" | "abort"
+///             | "memerror" | "error" | "typecheck" | "compile" | "infloop"
+/// comment   ::= "//" ... | "/*" ...
+/// ```
+fn parse_line(line: &str, line_number: usize, relax: bool) -> Result<Expectation, ParseError> {
+    let mut header = Header::new(line, line_number);
+
+    if !header.prefix() {
+        return Err(ParseError::MissingDirective(header.line.to_string()));
     }
+    header.expect_whitespace("test")?;
+    let flags = header.condition()?;
 
     use TestResult::*;
-    match words[1] {
+    let keyword_pos = header.pos;
+    let directive = match header.word() {
         "return" => {
-            if words.len() != 3 {
-                bail!("Expected return test directive to have integer instead got: {first_line}")
+            header.expect_whitespace("return")?;
+            Ret(header.int()?)
+        }
+        "div-by-zero" => DivByZero,
+        "abort" => Abort,
+        "memerror" => MemError,
+        "error" => SourceError,
+        "typecheck" => TypeCheck,
+        "compile" => Compile,
+        "infloop" => InfLoop,
+        "" => return Err(header.error(format!("expected a test directive {DIRECTIVES}"))),
+        r => {
+            header.pos = keyword_pos;
+            return Err(header.error(format!(
+                "expected a test directive {DIRECTIVES} instead got: {r}"
+            )));
+        }
+    };
+
+    header.skip_whitespace();
+    let rest = header.rest();
+    if !(relax || rest.is_empty() || rest.starts_with("//") || rest.starts_with("/*")) {
+        return Err(header.error(format!("unexpected `{rest}` after the directive")));
+    }
+
+    Ok(Expectation {
+        flags,
+        result: directive,
+    })
+}
+
+/// Cursor over a header line that knows where it is for error messages
+#[derive(Clone)]
+struct Header<'a> {
+    line: &'a str,
+    line_number: usize,
+    /// Byte offset into `line`
+    pos: usize,
+}
+
+impl<'a> Header<'a> {
+    fn new(line: &'a str, line_number: usize) -> Self {
+        Self {
+            line: line.trim_end(),
+            line_number,
+            pos: 0,
+        }
+    }
+
+    /// Consumes the `//test` that every header line starts with
+    fn prefix(&mut self) -> bool {
+        self.skip_whitespace();
+        if !self.eat("//") {
+            return false;
+        }
+        self.skip_whitespace();
+        self.word() == "test"
+    }
+
+    /// The flags before the `:` of a conditional directive, if there are any
+    fn condition(&mut self) -> Result<Vec<String>, ParseError> {
+        let mut flags = Vec::new();
+        while self.rest().starts_with('-') {
+            flags.push(
+                self.take_while(|c| !c.is_whitespace() && c != ':')
+                    .to_string(),
+            );
+            self.skip_whitespace();
+            if self.eat(":") {
+                self.skip_whitespace();
+                flags.sort();
+                return Ok(flags);
             }
+        }
 
-            // TODO: we need to check that the test verifier and this agree
-            // this allows non digit suffixes
-            let int_result : i32 = if words[2].as_bytes().last().map_or(false, |c| !c.is_ascii_digit()) {
-                words[2].strip_suffix(|c: char| !c.is_ascii_digit()).unwrap()
-            } else {
-                words[2]
-            }.parse()?;
+        if !flags.is_empty() {
+            return Err(self.error("expected `:` after the compiler flags".to_string()));
+        }
+        Ok(flags)
+    }
+
+    /// Whether the cursor is at flags followed by a `:`, without consuming them
+    fn is_condition(&self) -> bool {
+        let mut lookahead = self.clone();
+        while lookahead.rest().starts_with('-') {
+            lookahead.take_while(|c| !c.is_whitespace() && c != ':');
+            lookahead.skip_whitespace();
+            if lookahead.rest().starts_with(':') {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.line[self.pos..]
+    }
+
+    fn error(&self, message: String) -> ParseError {
+        ParseError::InvalidDirective {
+            line: self.line_number,
+            column: self.line[..self.pos].chars().count() + 1,
+            message,
+        }
+    }
+
+    fn eat(&mut self, prefix: &str) -> bool {
+        let found = self.rest().starts_with(prefix);
+        if found {
+            self.pos += prefix.len();
+        }
+        found
+    }
+
+    /// Consumes characters while `pred` holds, returning them
+    fn take_while(&mut self, pred: impl Fn(char) -> bool) -> &'a str {
+        let rest = self.rest();
+        let len = rest.find(|c| !pred(c)).unwrap_or(rest.len());
+        self.pos += len;
+        &rest[..len]
+    }
+
+    fn skip_whitespace(&mut self) -> bool {
+        !self.take_while(char::is_whitespace).is_empty()
+    }
+
+    fn expect_whitespace(&mut self, after: &str) -> Result<(), ParseError> {
+        if !self.skip_whitespace() {
+            let found = match self.rest().chars().next() {
+                Some(c) => format!("`{c}`"),
+                None => "the end of the line".to_string(),
+            };
+            return Err(self.error(format!(
+                "expected whitespace after `{after}` instead got {found}"
+            )));
+        }
+        Ok(())
+    }
+
+    fn word(&mut self) -> &'a str {
+        self.take_while(|c| c.is_ascii_alphanumeric() || c == '-')
+    }
+
+    /// A signed decimal that fits in a 32-bit C0 int
+    fn int(&mut self) -> Result<i32, ParseError> {
+        let start = self.pos;
+        let _ = self.eat("+") || self.eat("-");
+        let digits = self.take_while(|c| c.is_ascii_digit());
+        let text = &self.line[start..self.pos];
 
-            Ok(Ret(int_result))
+        if digits.is_empty() {
+            self.pos = start;
+            return Err(self.error("expected an integer return value".to_string()));
         }
-        "div-by-zero" => Ok(DivByZero),
-        "abort" => Ok(Abort),
-        "memerror" => Ok(MemError),
-        "error" => Ok(SourceError),
-        "typecheck" => Ok(TypeCheck),
-        "compile" => Ok(Compile),
-        r => bail!("Expected a test directive return | div-by-zero | abort | memerror | error | typecheck | compile instead got: {r}")
+
+        text.parse().map_err(|_| {
+            self.pos = start;
+            self.error(format!("return value {text} does not fit in a 32-bit int"))
+        })
     }
 }
 
@@ -91,13 +386,160 @@ fn parse_line(first_line: &str) -> Result<TestResult> {
 mod tests {
     use super::*;
 
+    fn directive(line: &str, relax: bool) -> Result<TestResult, ParseError> {
+        parse_line(line, 1, relax).map(|expectation| expectation.result)
+    }
+
     #[test]
     fn test1() {
         let first_line = "//test return 21212121\n";
-        println!("RET {:?}", parse_line(&first_line));
+        println!("RET {:?}", directive(first_line, false));
         assert!(matches!(
-            parse_line(&first_line),
+            directive(first_line, false),
             Ok(TestResult::Ret(21212121))
         ));
     }
+
+    #[test]
+    fn empty_and_headerless_files() {
+        assert!(matches!(
+            get_line(&b""[..], false),
+            Err(ParseError::EmptyFile)
+        ));
+        assert!(matches!(
+            directive("int main() {\n", false),
+            Err(ParseError::MissingDirective(_))
+        ));
+    }
+
+    #[test]
+    fn accepted_headers() {
+        use TestResult::*;
+        let accepted = [
+            ("//test return 3\n", Ret(3)),
+            ("// test return 3", Ret(3)),
+            ("  //test   return -5  \r\n", Ret(-5)),
+            ("//test return +7", Ret(7)),
+            ("//test return -2147483648", Ret(i32::MIN)),
+            ("//test return 2147483647", Ret(i32::MAX)),
+            ("//test error // missing semicolon", SourceError),
+            ("//test div-by-zero /* 1 / 0 */", DivByZero),
+            ("//\ttest\tmemerror", MemError),
+            ("//test infloop", InfLoop),
+        ];
+
+        for (header, expected) in accepted {
+            match directive(header, false) {
+                Ok(directive) => assert_eq!(directive, expected, "{header:?}"),
+                Err(e) => panic!("{header:?} was rejected: {e}"),
+            }
+        }
+    }
+
+    #[test]
+    fn rejected_headers() {
+        let rejected = [
+            ("//test return 3;", 16),
+            ("//test return 12abc", 17),
+            ("//test return", 14),
+            ("//test return x", 15),
+            ("//test return - 5", 15),
+            ("//test return 2147483648", 15),
+            ("//test return -2147483649", 15),
+            ("//test returns 3", 8),
+            ("//test", 7),
+            ("//test abort please", 14),
+            ("//test: abort", 7),
+        ];
+
+        for (header, column) in rejected {
+            match directive(header, false) {
+                Err(ParseError::InvalidDirective {
+                    line: 1, column: c, ..
+                }) => {
+                    assert_eq!(c, column, "{header:?}")
+                }
+                other => panic!("{header:?} should be invalid, got {other:?}"),
+            }
+        }
+
+        for header in ["/* test return 3 */", "// return 3", "//testing return 3"] {
+            assert!(
+                matches!(
+                    directive(header, false),
+                    Err(ParseError::MissingDirective(_))
+                ),
+                "{header:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn relaxed_headers() {
+        assert!(matches!(
+            directive("//test return 3;", true),
+            Ok(TestResult::Ret(3))
+        ));
+        assert!(matches!(
+            directive("//test abort please", true),
+            Ok(TestResult::Abort)
+        ));
+        // Relaxing never lets through an out of range return value
+        assert!(directive("//test return 99999999999", true).is_err());
+
+        let (line_number, header) = get_line(&b"\n  \n//test compile\n"[..], true).unwrap();
+        assert_eq!(line_number, 3);
+        assert!(matches!(
+            parse_line(&header, line_number, true).map(|expectation| expectation.result),
+            Ok(TestResult::Compile)
+        ));
+    }
+
+    #[test]
+    fn conditional_headers() {
+        let source = "//test return 0\n//test --unsafe: return 5\n//test -O1 --unsafe: infloop\n// test comments don't continue the header\n//test --safe: abort\n";
+        let header = read_header(source.as_bytes(), false).unwrap();
+        assert_eq!(header.expectations.len(), 3);
+        assert_eq!(header.expectations[2].flags, vec!["--unsafe", "-O1"]);
+
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        assert_eq!(header.expected_for(&[]), Some(&TestResult::Ret(0)));
+        assert_eq!(
+            header.expected_for(&args(&["--unsafe"])),
+            Some(&TestResult::Ret(5))
+        );
+        assert_eq!(
+            header.expected_for(&args(&["--unsafe", "-O1"])),
+            Some(&TestResult::InfLoop)
+        );
+
+        let header = read_header("//test --unsafe: return 5\n".as_bytes(), false).unwrap();
+        assert_eq!(header.expected_for(&[]), None);
+
+        // A comment that only looks like flags ends the header instead of
+        // making the test malformed
+        for source in [
+            "//test return 1\n// test -1 should wrap around\n",
+            "//test return 1\n//test --unsafe return 5\n",
+        ] {
+            for relax in [false, true] {
+                let header = read_header(source.as_bytes(), relax).unwrap();
+                assert_eq!(header.expectations.len(), 1, "{source:?}");
+                assert_eq!(header.expected_for(&[]), Some(&TestResult::Ret(1)));
+            }
+        }
+
+        for source in [
+            "//test return 0\n//test --unsafe: returns 5\n",
+            "//test return 0\n//test --unsafe: return 5\n//test --unsafe: return 6\n",
+        ] {
+            assert!(
+                matches!(
+                    read_header(source.as_bytes(), false),
+                    Err(ParseError::InvalidDirective { line: 2 | 3, .. })
+                ),
+                "{source:?}"
+            );
+        }
+    }
 }
@@ -0,0 +1,271 @@
+use std::io::{self, Read};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::interrupt;
+
+/// Most bytes kept from each of a subprocess's stdout and stderr. Only the tail
+/// is kept, since that is where a test's return value is printed.
+pub const MAX_CAPTURED_OUTPUT: usize = 1 << 20;
+
+/// How long past its limit a subprocess's output is still collected, so what
+/// is already sitting in the pipes isn't lost when it exits right at the limit
+const PIPE_GRACE: Duration = Duration::from_millis(100);
+
+/// Output of a subprocess that was run under a time limit
+#[derive(Debug)]
+pub struct TimedOutput {
+    /// `None` if the process was killed for exceeding its limit
+    pub status: Option<ExitStatus>,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    pub elapsed: Duration,
+}
+
+impl TimedOutput {
+    pub fn timed_out(&self) -> bool {
+        self.status.is_none()
+    }
+
+    pub fn success(&self) -> bool {
+        self.status.is_some_and(|s| s.success())
+    }
+}
+
+/// Like `Command::output` but kills the process once `limit` has elapsed, and
+/// everything else in its process group once it has exited, and keeps at most
+/// `MAX_CAPTURED_OUTPUT` bytes of each stream
+pub fn output_with_timeout(cmd: &mut Command, limit: Duration) -> io::Result<TimedOutput> {
+    let start = Instant::now();
+    let mut child = interrupt::spawn_in_group(
+        cmd.stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped()),
+    )?;
+
+    // Drain both pipes while waiting so a chatty child can't block on a full pipe
+    let stdout = Drain::spawn(child.stdout.take());
+    let stderr = Drain::spawn(child.stderr.take());
+
+    let status = wait_or_kill(&mut child, limit);
+    interrupt::untrack_group(child.id());
+    let status = status?;
+
+    // Anything the child forked may still hold the pipes open, so reading them
+    // is held to the same limit as the child itself
+    let deadline = (start + limit).max(Instant::now() + PIPE_GRACE);
+    Ok(TimedOutput {
+        status,
+        stdout: stdout.finish(deadline),
+        stderr: stderr.finish(deadline),
+        elapsed: start.elapsed(),
+    })
+}
+
+/// Like `Command::status` but with the same limit and process group handling as
+/// `output_with_timeout`, for output that goes wherever `cmd` sends it. `None`
+/// means the process was killed for exceeding its limit.
+pub fn status_with_timeout(cmd: &mut Command, limit: Duration) -> io::Result<Option<ExitStatus>> {
+    let mut child = interrupt::spawn_in_group(cmd.stdin(Stdio::null()))?;
+    let status = wait_or_kill(&mut child, limit);
+    interrupt::untrack_group(child.id());
+    status
+}
+
+/// The conventional name of a signal, e.g. `SIGSEGV`
+pub fn signal_name(signal: i32) -> String {
+    let name = match signal {
+        libc::SIGHUP => "SIGHUP",
+        libc::SIGINT => "SIGINT",
+        libc::SIGQUIT => "SIGQUIT",
+        libc::SIGILL => "SIGILL",
+        libc::SIGTRAP => "SIGTRAP",
+        libc::SIGABRT => "SIGABRT",
+        libc::SIGBUS => "SIGBUS",
+        libc::SIGFPE => "SIGFPE",
+        libc::SIGKILL => "SIGKILL",
+        libc::SIGUSR1 => "SIGUSR1",
+        libc::SIGSEGV => "SIGSEGV",
+        libc::SIGUSR2 => "SIGUSR2",
+        libc::SIGPIPE => "SIGPIPE",
+        libc::SIGALRM => "SIGALRM",
+        libc::SIGTERM => "SIGTERM",
+        libc::SIGXCPU => "SIGXCPU",
+        libc::SIGXFSZ => "SIGXFSZ",
+        libc::SIGSYS => "SIGSYS",
+        other => return format!("signal {other}"),
+    };
+    name.to_string()
+}
+
+/// Describes how a process ended, `None` meaning it was killed at its time limit
+pub fn describe_status(status: Option<ExitStatus>) -> String {
+    match status {
+        None => "timed out".to_string(),
+        Some(status) => match (status.code(), status.signal()) {
+            (Some(code), _) => format!("exit {code}"),
+            (None, Some(signal)) => format!("killed by {}", signal_name(signal)),
+            (None, None) => format!("{status}"),
+        },
+    }
+}
+
+/// rlimits set in a subprocess between fork and exec. `None` leaves a
+/// resource as it was inherited.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ResourceLimits {
+    /// Bytes of address space, so a runaway allocation fails inside the process
+    pub address_space: Option<u64>,
+    /// Bytes of stack
+    pub stack: Option<u64>,
+    /// Seconds of CPU time before SIGXCPU, with SIGKILL following a second later
+    pub cpu_seconds: Option<u64>,
+    /// Bytes in the largest file the process can write, enforced with SIGXFSZ
+    pub file_size: Option<u64>,
+    /// Processes the user may have, so a small value keeps the process from forking.
+    /// Ignored for root.
+    pub processes: Option<u64>,
+}
+
+impl ResourceLimits {
+    /// Makes `cmd` set these limits in the child before it execs
+    pub fn apply_to(self, cmd: &mut Command) {
+        // SAFETY: the hook only makes the getrlimit and setrlimit syscalls, which
+        // are async-signal-safe, and doesn't allocate
+        unsafe {
+            cmd.pre_exec(move || self.set());
+        }
+    }
+
+    fn set(&self) -> io::Result<()> {
+        let limits = [
+            (libc::RLIMIT_AS, self.address_space, 0),
+            (libc::RLIMIT_STACK, self.stack, 0),
+            (libc::RLIMIT_CPU, self.cpu_seconds, 1),
+            (libc::RLIMIT_FSIZE, self.file_size, 0),
+            (libc::RLIMIT_NPROC, self.processes, 0),
+        ];
+
+        for (resource, limit, grace) in limits {
+            let Some(limit) = limit else {
+                continue;
+            };
+
+            let mut current = libc::rlimit {
+                rlim_cur: 0,
+                rlim_max: 0,
+            };
+            // An unprivileged process can't raise its hard limit, so stay under it
+            if unsafe { libc::getrlimit(resource, &mut current) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let ceiling = current.rlim_max;
+            let new = libc::rlimit {
+                rlim_cur: limit.min(ceiling),
+                rlim_max: limit.saturating_add(grace).min(ceiling),
+            };
+            if unsafe { libc::setrlimit(resource, &new) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Waits for `child` to exit or for `limit` to elapse, then kills whatever is
+/// left of its process group so nothing it forked outlives it
+fn wait_or_kill(child: &mut Child, limit: Duration) -> io::Result<Option<ExitStatus>> {
+    let exited = wait_without_reaping(child, limit)?;
+    // The child hasn't been reaped yet, so its pid can't have been reused as the
+    // id of some other group
+    interrupt::kill_group(child.id());
+    let status = child.wait()?;
+    Ok(exited.then_some(status))
+}
+
+/// Whether `child` exited within `limit`, leaving it a zombie until it's waited on
+fn wait_without_reaping(child: &Child, limit: Duration) -> io::Result<bool> {
+    let deadline = Instant::now() + limit;
+    let mut backoff = Duration::from_micros(100);
+    loop {
+        let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+        let ret = unsafe {
+            libc::waitid(
+                libc::P_PID,
+                child.id(),
+                &mut info,
+                libc::WEXITED | libc::WNOWAIT | libc::WNOHANG,
+            )
+        };
+        if ret != 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        } else if unsafe { info.si_pid() } != 0 {
+            return Ok(true);
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(false);
+        }
+        thread::sleep(backoff.min(deadline - now));
+        backoff = (backoff * 2).min(Duration::from_millis(10));
+    }
+}
+
+/// A pipe being read into memory on a background thread
+struct Drain {
+    buf: Arc<Mutex<Vec<u8>>>,
+    /// Disconnects once the pipe has closed
+    closed: Receiver<()>,
+}
+
+impl Drain {
+    fn spawn<R>(pipe: Option<R>) -> Self
+    where
+        R: Read + Send + 'static,
+    {
+        let buf = Arc::new(Mutex::new(Vec::new()));
+        let shared = Arc::clone(&buf);
+        let (closed_tx, closed) = mpsc::channel();
+        thread::spawn(move || {
+            let _closed_tx = closed_tx;
+            let Some(mut pipe) = pipe else {
+                return;
+            };
+            let mut chunk = [0; 8192];
+            while let Ok(n @ 1..) = pipe.read(&mut chunk) {
+                let mut buf = shared.lock().unwrap();
+                buf.extend_from_slice(&chunk[..n]);
+                // Trim in batches rather than on every read
+                if buf.len() > 2 * MAX_CAPTURED_OUTPUT {
+                    let excess = buf.len() - MAX_CAPTURED_OUTPUT;
+                    buf.drain(..excess);
+                }
+            }
+        });
+
+        Self { buf, closed }
+    }
+
+    /// Waits until the pipe closes or `deadline` passes, whichever is first, and
+    /// returns the tail of what was read from it
+    fn finish(self, deadline: Instant) -> Vec<u8> {
+        let _ = self
+            .closed
+            .recv_timeout(deadline.saturating_duration_since(Instant::now()));
+        captured_tail(&self.buf)
+    }
+}
+
+fn captured_tail(buf: &Mutex<Vec<u8>>) -> Vec<u8> {
+    let buf = buf.lock().unwrap();
+    buf[buf.len().saturating_sub(MAX_CAPTURED_OUTPUT)..].to_vec()
+}
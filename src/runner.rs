@@ -1,46 +1,142 @@
-use anyhow::{anyhow, bail, Context, Error, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use colored::Colorize;
-use serde::{Deserialize, Serialize};
-use std::fs::canonicalize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs::File;
+use std::io::Write;
 use std::os::unix::process::ExitStatusExt;
-use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::{
     env, fs,
-    path::{self, Path, PathBuf},
+    path::{Path, PathBuf},
     process::{Command, ExitStatus},
-    thread,
-    time::{Duration, Instant},
+    time::Duration,
 };
 use thiserror::Error;
-use wait_timeout::ChildExt;
 
-use std::fs::File;
-use std::io::{self, BufRead, Read, Write};
 use tempdir::TempDir;
 
 use crate::{
+    bench::{
+        self, format_speedups, format_table, scored_ratio, BenchReport, BenchStats,
+        PerformanceResults, Sample, Speedup, NOISY_SPREAD,
+    },
     config::Cli,
-    parser::{self, TestResult},
-    runner_file_utils::process_files_parallel,
+    emit::EmitMode,
+    interrupt,
+    parser::TestResult,
+    process::{
+        describe_status, output_with_timeout, signal_name, status_with_timeout, ResourceLimits,
+        TimedOutput,
+    },
+    runner_file_utils::{collect_files, process_files_parallel},
+    sandbox::Sandbox,
+    scoring::{OutcomeKind, PerformancePolicy, ScoringPolicy, REFERENCE_LEVELS},
+    selection::{
+        parse_tests, select_applicable, select_by_safety, select_infloop, select_tests,
+        test_extension, KeepList, Test, KEEP_FILE,
+    },
+    test_log::{remove_log_dir, reset_log_dir, write_summary, TestLog, LOG_DIR},
+    vetting::vet_tests,
 };
 
+/// Where the output of building the student compiler is saved in the log directory
+const MAKE_LOG: &str = "make.log";
+
+/// The subprocess a test was in when it hit its time limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase {
+    Typecheck,
+    Compile,
+    Link,
+    Run,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Phase::Typecheck => write!(f, "typecheck"),
+            Phase::Compile => write!(f, "compile"),
+            Phase::Link => write!(f, "link"),
+            Phase::Run => write!(f, "run"),
+        }
+    }
+}
+
 #[derive(Debug)]
 enum TestOutcome {
-    Passed,   // 1.0
-    TimedOut, // -0.1
-    Failed,   // -1.0
-              // TODO: store incorrect result
+    Passed,
+    TimedOut(Phase),
+    Failed {
+        expected: TestResult,
+        observed: Observed,
+    },
+    /// Rejected by static analysis before running
+    FailedVetting(String),
+    /// The directive disagrees with the reference compiler
+    BadTest {
+        expected: TestResult,
+        reference: Observed,
+    },
+}
+
+impl fmt::Display for TestOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TestOutcome::Passed => write!(f, "passed"),
+            TestOutcome::TimedOut(phase) => write!(f, "{phase} timeout"),
+            TestOutcome::Failed { expected, observed } => {
+                write!(f, "failed: expected `{expected}`, but {observed}")
+            }
+            TestOutcome::FailedVetting(reason) => write!(f, "failed: {reason}"),
+            TestOutcome::BadTest {
+                expected,
+                reference,
+            } => write!(
+                f,
+                "bad test: expected `{expected}`, but with the reference compiler {reference}"
+            ),
+        }
+    }
+}
+
+impl TestOutcome {
+    fn kind(&self) -> OutcomeKind {
+        match self {
+            TestOutcome::Passed => OutcomeKind::Passed,
+            TestOutcome::TimedOut(_) => OutcomeKind::Timeout,
+            TestOutcome::Failed { .. } | TestOutcome::FailedVetting(_) => OutcomeKind::Failed,
+            TestOutcome::BadTest { .. } => OutcomeKind::BadTest,
+        }
+    }
 }
 
 #[derive(Error, Debug)]
 enum TestFailure {
-    #[error("Compiler failed")]
-    CompileFailure, // -1.0
-    #[error("Test was malformed")]
-    MalformedTest, // 0.0
+    /// The test's directive couldn't be parsed, so there is nothing to grade against
+    #[error("Test was malformed: {0}")]
+    MalformedTest(String),
+    /// The reference compiler couldn't be run, linked against or run the program
+    /// it built, which says nothing about the test
+    #[error("The reference compiler failed with `{command}`: {error}")]
+    ReferenceFailed { command: String, error: String },
 }
 
-#[derive(Debug)]
+/// Which scoring category a graded test, or the error grading it, falls into
+fn outcome_kind(outcome: &Result<TestOutcome>) -> OutcomeKind {
+    match outcome {
+        Ok(outcome) => outcome.kind(),
+        Err(e) if matches!(e.downcast_ref(), Some(TestFailure::MalformedTest(_))) => {
+            OutcomeKind::Malformed
+        }
+        Err(_) => OutcomeKind::Failed,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum ProcessResult {
     Success(i32),
     Failure(i32),
@@ -48,40 +144,751 @@ pub enum ProcessResult {
     SignalAbort,
     SignalUsr2,
     SigFpe,
+    /// SIGSEGV, which is also how running out of stack shows up
+    SegFault,
+    /// SIGKILL, usually from going over the hard CPU limit
+    Killed,
+    /// SIGXCPU from going over --limit-cpu
+    CpuLimit,
+    /// SIGXFSZ from writing a file larger than --limit-file-size
+    FileSizeLimit,
     OtherSignal(i32),
+    /// Exited successfully without printing a return value as its last line
+    BadOutput {
+        /// The tail of what was printed
+        output: Vec<u8>,
+        truncated: bool,
+    },
+}
+
+impl fmt::Display for ProcessResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProcessResult::Success(ret) => write!(f, "returned {ret}"),
+            ProcessResult::Failure(code) => write!(f, "exited with status {code}"),
+            ProcessResult::Timeout => write!(f, "timed out"),
+            ProcessResult::SignalAbort => write!(f, "raised SIGABRT"),
+            ProcessResult::SignalUsr2 => write!(f, "raised SIGUSR2"),
+            ProcessResult::SigFpe => write!(f, "raised SIGFPE"),
+            ProcessResult::SegFault => write!(f, "raised SIGSEGV"),
+            ProcessResult::Killed => write!(f, "was killed with SIGKILL"),
+            ProcessResult::CpuLimit => write!(f, "went over the CPU time limit (SIGXCPU)"),
+            ProcessResult::FileSizeLimit => {
+                write!(f, "went over the file size limit (SIGXFSZ)")
+            }
+            ProcessResult::OtherSignal(signal) => write!(f, "raised {}", signal_name(*signal)),
+            ProcessResult::BadOutput { output, truncated } if output.is_empty() && !truncated => {
+                write!(f, "exited without printing a return value")
+            }
+            ProcessResult::BadOutput { output, truncated } => write!(
+                f,
+                "printed \"{}{}\" instead of a return value",
+                if *truncated { "..." } else { "" },
+                output.escape_ascii()
+            ),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Default)]
 pub struct FinalScore {
     passed: usize,
     failed: usize,
-    timeout: usize,
+    /// Tests that timed out, by the phase they timed out in
+    timeouts: BTreeMap<Phase, usize>,
+    bad_test: usize,
+    malformed: usize,
+    /// Points contributed by each outcome, and by `performance` with --bench and
+    /// --cc0, before lab caps and the floor
+    points: BTreeMap<&'static str, f32>,
+    /// Points contributed by each lab, after its cap
+    labs: BTreeMap<String, f32>,
+    score: f32,
+}
+
+impl fmt::Display for FinalScore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "passed: {}, failed: {}, timeout: {}, bad test: {}, malformed: {}",
+            self.passed,
+            self.failed,
+            self.timeout(),
+            self.bad_test,
+            self.malformed
+        )
+    }
+}
+
+/// How a single test was graded
+#[derive(Debug, Serialize)]
+pub struct TestReport {
+    /// See [`Test::name`]
+    pub name: String,
+    pub directive: Option<String>,
+    pub passed: bool,
+    /// e.g. "passed", "run timeout" or
+    /// "failed: expected `return 3`, but the program returned 4"
+    pub outcome: String,
+    /// With --cc0, how the student's result differed from the reference
+    /// compiler's, even if the test passed
+    pub differs_from_reference: Option<String>,
+    pub score: f32,
+    pub max_score: f32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RunResults {
+    pub score: FinalScore,
+    pub tests: Vec<TestReport>,
+    /// Empty unless --bench
+    pub bench: Vec<BenchReport>,
+    /// Only with --bench and --cc0
+    pub performance: Option<PerformanceResults>,
 }
 
 impl FinalScore {
     pub fn to_score(&self) -> f32 {
-        ((self.passed - self.failed) as f32) + ((self.timeout as f32) * 0.1)
+        self.score
+    }
+
+    /// Tests that timed out in any phase
+    pub fn timeout(&self) -> usize {
+        self.timeouts.values().sum()
+    }
+}
+
+/// What a compiler, and the program it produced, did with a single test
+#[derive(Debug, Clone, PartialEq)]
+pub enum Observed {
+    /// The compiler reported an error in the test by exiting with status 1
+    Rejected,
+    /// The compiler crashed or exited with some other status while compiling
+    CompilerFailed(ProcessResult),
+    /// The compiler succeeded and the test doesn't need to be run
+    Accepted,
+    /// The compiler or linker hit its time limit
+    TimedOut(Phase),
+    Ran(ProcessResult),
+}
+
+impl fmt::Display for Observed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Observed::Rejected => write!(f, "the compiler rejected the test"),
+            Observed::Accepted => write!(f, "the compiler accepted the test"),
+            Observed::CompilerFailed(result) => write!(f, "the compiler {result}"),
+            Observed::TimedOut(phase) => write!(f, "the {phase} step timed out"),
+            Observed::Ran(result) => write!(f, "the program {result}"),
+        }
     }
 }
 
-fn add_extension(path: &PathBuf, extension: impl AsRef<Path>) -> PathBuf {
-    let mut path = path.clone();
-    match path.extension() {
-        Some(ext) => {
-            let mut ext = ext.to_os_string();
-            ext.push(".");
-            ext.push(extension.as_ref());
-            path.set_extension(ext);
-            path
+fn meets_directive(expected: &TestResult, observed: &Observed) -> bool {
+    match (expected, observed) {
+        (TestResult::SourceError, Observed::Rejected) => true,
+        (TestResult::SourceError, _) => false,
+        (_, Observed::Accepted) => true,
+        (TestResult::Ret(r), Observed::Ran(ProcessResult::Success(o))) => r == o,
+        (TestResult::Abort, Observed::Ran(ProcessResult::SignalAbort))
+        | (TestResult::MemError, Observed::Ran(ProcessResult::SignalUsr2))
+        | (TestResult::DivByZero, Observed::Ran(ProcessResult::SigFpe))
+        | (TestResult::InfLoop, Observed::Ran(ProcessResult::Timeout | ProcessResult::CpuLimit)) => {
+            true
         }
+        _ => false,
+    }
+}
+
+/// Bytes of unparseable program output kept for reporting
+const REPORTED_OUTPUT: usize = 80;
+
+/// Reads the return value run411.c prints as the last line of a successful run
+fn parse_return_value(stdout: &[u8]) -> ProcessResult {
+    // Only the last line has to be text. The captured output is cut at an
+    // arbitrary byte, which can split a character anywhere before it.
+    let last_line = stdout
+        .strip_suffix(b"\n")
+        .unwrap_or(stdout)
+        .rsplit(|b| *b == b'\n')
+        .next()
+        .unwrap_or_default();
+    let return_value = std::str::from_utf8(last_line)
+        .ok()
+        .and_then(|last_line| last_line.trim().parse().ok());
+
+    match return_value {
+        Some(return_value) => ProcessResult::Success(return_value),
         None => {
-            path.set_extension(extension.as_ref());
-            path
+            let tail = stdout.len().saturating_sub(REPORTED_OUTPUT);
+            ProcessResult::BadOutput {
+                output: stdout[tail..].to_vec(),
+                truncated: tail > 0,
+            }
+        }
+    }
+}
+
+fn classify_exit(status: ExitStatus) -> ProcessResult {
+    if let Some(exit_code) = status.code() {
+        ProcessResult::Failure(exit_code)
+    } else {
+        match status.signal().unwrap() {
+            libc::SIGABRT => ProcessResult::SignalAbort,
+            libc::SIGFPE => ProcessResult::SigFpe,
+            libc::SIGUSR2 => ProcessResult::SignalUsr2,
+            libc::SIGSEGV => ProcessResult::SegFault,
+            libc::SIGKILL => ProcessResult::Killed,
+            libc::SIGXCPU => ProcessResult::CpuLimit,
+            libc::SIGXFSZ => ProcessResult::FileSizeLimit,
+            other => ProcessResult::OtherSignal(other),
+        }
+    }
+}
+
+/// What the compiler's exit `status` in `phase` says about the test, or `None`
+/// if it succeeded and the test goes on. A missing status is a timeout.
+fn classify_compiler_exit(status: Option<ExitStatus>, phase: Phase) -> Option<Observed> {
+    match status {
+        None => Some(Observed::TimedOut(phase)),
+        Some(status) if status.success() => None,
+        // Exiting with status 1 is how a compiler reports an error in the test.
+        // Signals, panics and any other status are bugs in the compiler and
+        // don't count as rejecting the test.
+        Some(status) if status.code() == Some(1) => Some(Observed::Rejected),
+        Some(status) => Some(Observed::CompilerFailed(classify_exit(status))),
+    }
+}
+
+/// Renders a command the way it would be typed into a shell, for reproducing failures
+fn display_command(cmd: &Command) -> String {
+    std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(|arg| arg.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn limit(seconds: u32) -> Duration {
+    Duration::from_secs(seconds.into())
+}
+
+fn failed(test_name: &OsStr, outcome: TestOutcome) -> TestOutcome {
+    println!("{}", format!("{test_name:?} {outcome}").red());
+    outcome
+}
+
+fn timed_out(test_name: &OsStr, phase: Phase) -> TestOutcome {
+    println!("{}", format!("{test_name:?} {phase} timeout").yellow());
+    TestOutcome::TimedOut(phase)
+}
+
+/// Takes a single test through a compiler, shared by every test in a run
+#[derive(Clone)]
+struct Harness<'a> {
+    config: &'a Cli,
+    compiler_args: Vec<String>,
+    emit_mode: EmitMode,
+    frontend_phase: Phase,
+    frontend_flag: &'a str,
+    frontend_limit: u32,
+    /// rlimits for the test program
+    run_limits: ResourceLimits,
+    sandbox: Option<Sandbox>,
+}
+
+impl<'a> Harness<'a> {
+    fn new(config: &'a Cli) -> Result<Self> {
+        let emit_mode: EmitMode = config.emit.parse()?;
+        let (frontend_phase, frontend_flag, frontend_limit) = if config.typecheck_only {
+            (
+                Phase::Typecheck,
+                config.typecheck_flag.as_str(),
+                config.limit_tc,
+            )
+        } else {
+            (
+                Phase::Compile,
+                emit_mode.compiler_flag(),
+                config.limit_compile,
+            )
+        };
+
+        let mib = |mib: u64| (mib != 0).then_some(mib.saturating_mul(1 << 20));
+        let cpu_seconds = config.limit_cpu.unwrap_or(config.limit_run);
+        let run_limits = ResourceLimits {
+            address_space: mib(config.limit_memory),
+            stack: mib(config.limit_stack),
+            cpu_seconds: (cpu_seconds != 0).then_some(cpu_seconds.into()),
+            file_size: mib(config.limit_file_size),
+            processes: (config.limit_processes != 0).then_some(config.limit_processes),
+        };
+
+        Ok(Self {
+            config,
+            compiler_args: config.compiler_args()?,
+            emit_mode,
+            frontend_phase,
+            frontend_flag,
+            frontend_limit,
+            run_limits,
+            sandbox: Sandbox::new(config.sandbox.parse()?)?,
+        })
+    }
+
+    /// The same harness passing `compiler_args` to the compiler instead
+    fn with_args(&self, compiler_args: Vec<String>) -> Self {
+        Self {
+            compiler_args,
+            ..self.clone()
+        }
+    }
+
+    fn compiler_command(&self, compiler: &Path, test: &Path) -> Command {
+        let mut compiler_cmd = Command::new(compiler);
+        compiler_cmd
+            .arg(self.frontend_flag)
+            .args(&self.compiler_args)
+            .arg(test);
+        compiler_cmd
+    }
+
+    /// Compiles, links and runs `test` with `compiler`, stopping after the
+    /// frontend when the directive doesn't need the program to run
+    fn execute(
+        &self,
+        compiler: &Path,
+        test: &Path,
+        expected: &TestResult,
+        log: &mut TestLog,
+    ) -> Result<Observed> {
+        let mut run_cmd = match self.build(compiler, test, expected, log)? {
+            Build::Program(run_cmd) => run_cmd,
+            Build::Finished(observed) => return Ok(observed),
+        };
+
+        let run_command = display_command(&run_cmd);
+        let run_output = output_with_timeout(&mut run_cmd, limit(self.config.limit_run))
+            .with_context(|| format!("Failed to run: {run_command}"))?;
+        log.command("run", &run_command, &run_output);
+
+        Ok(Observed::Ran(program_result(&run_output)))
+    }
+
+    /// Compiles and links `test` with `compiler`, returning the command that
+    /// runs the program
+    fn build(
+        &self,
+        compiler: &Path,
+        test: &Path,
+        expected: &TestResult,
+        log: &mut TestLog,
+    ) -> Result<Build> {
+        let workdir = test
+            .parent()
+            .ok_or(anyhow!("Test {test:?} has no parent directory"))?;
+
+        let mut compiler_cmd = self.compiler_command(compiler, test);
+        let compile_command = display_command(&compiler_cmd);
+        if let Some(sandbox) = &self.sandbox {
+            sandbox.apply_to(&mut compiler_cmd, workdir)?;
+        }
+
+        let compiler_output = output_with_timeout(&mut compiler_cmd, limit(self.frontend_limit))
+            .with_context(|| format!("Failed to run: {compile_command}"))?;
+        log.command(
+            &self.frontend_phase.to_string(),
+            &compile_command,
+            &compiler_output,
+        );
+
+        if let Some(observed) = classify_compiler_exit(compiler_output.status, self.frontend_phase)
+        {
+            return Ok(Build::Finished(observed));
+        }
+
+        // Only the frontend is being graded, so accepting or rejecting is the whole test
+        if self.config.typecheck_only
+            || matches!(
+                expected,
+                TestResult::SourceError | TestResult::TypeCheck | TestResult::Compile
+            )
+        {
+            return Ok(Build::Finished(Observed::Accepted));
+        }
+
+        let artifact_path = self.emit_mode.artifact_path(test);
+        let out_path = workdir.join("a.out");
+
+        // Executables from the compiler don't need the runtime, so don't require it
+        let link_steps = if self.emit_mode.links_runtime() {
+            let runtime_path = fs::canonicalize(Path::new("../runtime"))?;
+            self.emit_mode
+                .link_steps(&artifact_path, &runtime_path, &out_path)
+        } else {
+            Vec::new()
+        };
+
+        for mut link_cmd in link_steps {
+            let link_command = display_command(&link_cmd);
+            let linked_output = output_with_timeout(&mut link_cmd, limit(self.config.limit_link))
+                .with_context(|| format!("Failed to run: {link_command}"))?;
+            log.command("link", &link_command, &linked_output);
+
+            if linked_output.timed_out() {
+                return Ok(Build::Finished(Observed::TimedOut(Phase::Link)));
+            }
+
+            if !linked_output.success() {
+                bail!(
+                    "Failed to link with: {link_command}\n\t{}",
+                    String::from_utf8_lossy(&linked_output.stderr)
+                );
+            }
+        }
+
+        let out_path = self.emit_mode.executable_path(&artifact_path, &out_path);
+        let mut run_cmd = Command::new(out_path);
+        if let Some(sandbox) = &self.sandbox {
+            sandbox.apply_to(&mut run_cmd, workdir)?;
+        }
+        self.run_limits.apply_to(&mut run_cmd);
+
+        Ok(Build::Program(run_cmd))
+    }
+}
+
+/// How far `Harness::build` got with a test
+enum Build {
+    /// The program is ready to be run with this command
+    Program(Command),
+    /// The test's result was decided before there was a program to run
+    Finished(Observed),
+}
+
+fn program_result(run_output: &TimedOutput) -> ProcessResult {
+    match run_output.status {
+        Some(status) if status.success() => parse_return_value(&run_output.stdout),
+        Some(status) => classify_exit(status),
+        None => ProcessResult::Timeout,
+    }
+}
+
+/// A compiler whose programs are benchmarked
+struct Contender<'a> {
+    harness: Harness<'a>,
+    compiler: &'a Path,
+    /// The optimization level for the reference compiler, `None` for the student's
+    level: Option<&'static str>,
+}
+
+impl fmt::Display for Contender<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.level {
+            None => write!(f, "the student compiler"),
+            Some(level) => write!(f, "the reference compiler at {level}"),
+        }
+    }
+}
+
+/// Runs each of `tests` --bench-runs times with `compiler`, one at a time so the
+/// measurements don't disturb each other. With a `reference` compiler, its
+/// programs at each of `REFERENCE_LEVELS` are run alongside the student's and
+/// the student's speedup over them is scored with `policy`. Tests that don't
+/// build or that stop meeting their directive are reported and left out.
+fn bench_tests(
+    harness: &Harness,
+    compiler: &Path,
+    reference: Option<&Path>,
+    tests: &[&Test],
+    test_root: &Path,
+    policy: &PerformancePolicy,
+) -> (Vec<BenchReport>, Option<PerformanceResults>) {
+    let mut contenders = vec![Contender {
+        harness: harness.clone(),
+        compiler,
+        level: None,
+    }];
+    if let Some(reference) = reference {
+        contenders.extend(REFERENCE_LEVELS.iter().map(|&level| Contender {
+            harness: harness.with_args(reference_args(&harness.compiler_args, level)),
+            compiler: reference,
+            level: Some(level),
+        }));
+    }
+
+    let mut reports = Vec::new();
+    let mut speedups = Vec::new();
+    for test in tests {
+        let name = test.name(test_root);
+        let Ok(expected) = &test.directive else {
+            continue;
+        };
+
+        let stats = match bench_test(&contenders, &test.path, expected) {
+            Ok(stats) => stats,
+            Err(e) => {
+                println!("{}", format!("{name:?} not benchmarked: {e:#}").yellow());
+                continue;
+            }
+        };
+        let (student, references) = stats.split_first().expect("the student always contends");
+
+        if reference.is_some() {
+            let speedup = score_speedup(name.clone(), student, references, policy);
+            if speedup.noisy {
+                println!(
+                    "{}",
+                    format!(
+                        "{name:?} varied by more than {:.0}% between runs, so a slowdown is not held against it",
+                        NOISY_SPREAD * 100.0
+                    )
+                    .yellow()
+                );
+            }
+            speedups.push(speedup);
+        }
+        reports.push(BenchReport {
+            name,
+            stats: student.clone(),
+        });
+    }
+
+    let performance = reference.map(|_| PerformanceResults {
+        policy: policy.clone(),
+        points: speedups.iter().map(|speedup| speedup.points).sum(),
+        tests: speedups,
+    });
+    (reports, performance)
+}
+
+/// Benchmarks the program each of `contenders` builds from `test`, in the same order
+fn bench_test(
+    contenders: &[Contender],
+    test: &Path,
+    expected: &TestResult,
+) -> Result<Vec<BenchStats>> {
+    let config = contenders[0].harness.config;
+
+    // The workdirs have to outlive the runs. They aren't kept with --debug, since
+    // grading already kept this test's artifacts and every run would add more.
+    let mut workdirs = Vec::new();
+    let mut programs = Vec::new();
+    for contender in contenders {
+        let (workdir, staged_path) = stage_test(test, "c0_bench", false)?;
+        let mut log = TestLog::default();
+        let build = contender
+            .harness
+            .build(contender.compiler, &staged_path, expected, &mut log)
+            .with_context(|| format!("Failed to build with {contender}"))?;
+        match build {
+            Build::Program(run_cmd) => programs.push(run_cmd),
+            Build::Finished(observed) => bail!("with {contender}, {observed}"),
+        }
+        workdirs.push(workdir);
+    }
+
+    // After a warm-up run that isn't counted, the programs take turns so that
+    // any drift in the state of the machine affects them all alike
+    let mut samples = vec![Vec::new(); programs.len()];
+    for run in 0..=config.bench_runs {
+        for ((run_cmd, contender), samples) in programs.iter_mut().zip(contenders).zip(&mut samples)
+        {
+            let (sample, output) = bench::execute_cmd(run_cmd, limit(config.limit_run))
+                .with_context(|| format!("Failed to run the program from {contender}"))?;
+            let observed = Observed::Ran(program_result(&output));
+            if !meets_directive(expected, &observed) {
+                bail!("with {contender}, expected `{expected}`, but {observed}");
+            }
+            if run > 0 {
+                samples.push(sample);
+            }
+        }
+    }
+
+    // Mixing cycles and CPU time would make the medians and the ratios between
+    // them meaningless
+    let unit = Sample::common_unit(&samples.concat());
+    Ok(samples
+        .iter()
+        .map(|samples| {
+            let values = samples.iter().map(|sample| sample.value(unit)).collect();
+            BenchStats::new(unit, values)
+        })
+        .collect())
+}
+
+/// The student's compiler args with any optimization level replaced by `level`
+fn reference_args(args: &[String], level: &str) -> Vec<String> {
+    args.iter()
+        .filter(|arg| !arg.starts_with("-O"))
+        .cloned()
+        .chain([level.to_string()])
+        .collect()
+}
+
+/// Scores the student's speedup over the reference compiler's programs, whose
+/// stats are in `REFERENCE_LEVELS` order
+fn score_speedup(
+    name: String,
+    student: &BenchStats,
+    references: &[BenchStats],
+    policy: &PerformancePolicy,
+) -> Speedup {
+    let levels: BTreeMap<_, _> = REFERENCE_LEVELS
+        .iter()
+        .map(|level| level.to_string())
+        .zip(references)
+        .collect();
+
+    // A program too quick to measure counts as one unit, rather than dividing by zero
+    let ratios: BTreeMap<_, _> = levels
+        .iter()
+        .map(|(level, stats)| {
+            (
+                level.clone(),
+                stats.median.max(1.0) / student.median.max(1.0),
+            )
+        })
+        .collect();
+    let against = &policy.against;
+    let points = policy.points(scored_ratio(ratios[against], student, levels[against]));
+
+    Speedup {
+        name,
+        unit: student.unit,
+        student: student.median,
+        reference: levels
+            .iter()
+            .map(|(level, stats)| (level.clone(), stats.median))
+            .collect(),
+        ratios,
+        points,
+        max_points: policy.max_points(),
+        noisy: student.spread() > NOISY_SPREAD || levels[against].spread() > NOISY_SPREAD,
+    }
+}
+
+/// Runs `make [<lab>]` in the CWD under --limit-make, writing its output straight
+/// to `MAKE_LOG` in the log directory as it builds
+fn build_compiler(config: &Cli) -> Result<()> {
+    let mut make_cmd = Command::new("make");
+    if let Some(par) = config.parallel {
+        make_cmd.args(["-j", par.to_string().as_str()]);
+    }
+    if let Some(lab) = &config.make {
+        make_cmd.arg(lab);
+    }
+    let make_command = display_command(&make_cmd);
+
+    let make_log = Path::new(LOG_DIR).join(MAKE_LOG);
+    let mut log =
+        File::create(&make_log).with_context(|| format!("Failed to create {make_log:?}"))?;
+    writeln!(log, "$ {make_command}")?;
+    make_cmd.stdout(log.try_clone()?).stderr(log);
+
+    println!("Building compiler with: {make_command}, logging to {make_log:?}");
+    let status = status_with_timeout(&mut make_cmd, limit(config.limit_make))
+        .with_context(|| format!("Failed to run: {make_command}"))?;
+
+    match status {
+        None => bail!(
+            "{make_command} did not finish within {} seconds, see {make_log:?}",
+            config.limit_make
+        ),
+        Some(status) if !status.success() => bail!(
+            "Expected {make_command} to succeed but failed with {}, see {make_log:?}:\n{}",
+            describe_status(Some(status)),
+            log_tail(&make_log)
+        ),
+        Some(_) => Ok(()),
+    }
+}
+
+/// Lines from the end of the make log shown when the build fails
+const MAKE_LOG_TAIL: usize = 20;
+
+fn log_tail(path: &Path) -> String {
+    let log = fs::read(path).unwrap_or_default();
+    let log = String::from_utf8_lossy(&log);
+    let lines: Vec<_> = log.lines().collect();
+    lines[lines.len().saturating_sub(MAKE_LOG_TAIL)..].join("\n")
+}
+
+/// Scratch directory for a single test's artifacts
+enum Workdir {
+    /// Deleted once the test is graded
+    Temp(TempDir),
+    /// Left on disk for --debug
+    Kept(PathBuf),
+}
+
+impl Drop for Workdir {
+    fn drop(&mut self) {
+        if let Workdir::Temp(tempdir) = self {
+            interrupt::untrack_dir(tempdir.path());
+        }
+    }
+}
+
+impl Workdir {
+    fn path(&self) -> &Path {
+        match self {
+            Workdir::Temp(tempdir) => tempdir.path(),
+            Workdir::Kept(path) => path,
         }
     }
 }
 
-pub fn make_and_run<P>(path: P, config: &Cli) -> Result<FinalScore>
+/// Compiles and runs a trivial test with the reference compiler, so one that
+/// can't be run or linked against is reported once up front rather than as a
+/// failure of every test
+fn probe_reference(harness: &Harness, reference: &Path) -> Result<()> {
+    let tempdir = TempDir::new("c0_probe")?;
+    interrupt::track_dir(tempdir.path());
+    let workdir = Workdir::Temp(tempdir);
+    let probe = workdir.path().join("probe.l1");
+    fs::write(&probe, "//test return 0\nint main() {\n  return 0;\n}\n")?;
+
+    let expected = TestResult::Ret(0);
+    let result = harness
+        .execute(reference, &probe, &expected, &mut TestLog::default())
+        .and_then(|observed| {
+            if !meets_directive(&expected, &observed) {
+                bail!("on a test that returns 0, {observed}");
+            }
+            Ok(())
+        });
+    result.map_err(|e| {
+        TestFailure::ReferenceFailed {
+            command: display_command(&harness.compiler_command(reference, &probe)),
+            error: format!("{e:#}"),
+        }
+        .into()
+    })
+}
+
+/// Copies `test` into a fresh tempdir so compiler artifacts don't land in the test directory
+fn stage_test(test: &Path, prefix: &str, keep: bool) -> Result<(Workdir, PathBuf)> {
+    let tempdir = TempDir::new(prefix)?;
+    let workdir = if keep {
+        Workdir::Kept(tempdir.into_path())
+    } else {
+        interrupt::track_dir(tempdir.path());
+        Workdir::Temp(tempdir)
+    };
+    let test_name = test
+        .file_name()
+        .ok_or(anyhow!("Couldn't extract file name from {test:?}"))?;
+    let staged_path = workdir.path().join(test_name);
+    fs::copy(test, &staged_path)?;
+    // Symlinks might be weird...
+    // symlink(p, &new_test_path)?;
+
+    Ok((workdir, staged_path))
+}
+
+pub fn make_and_run<P>(path: P, config: &Cli) -> Result<RunResults>
 where
     P: AsRef<Path>,
 {
@@ -93,208 +900,486 @@ where
 
     println!("Looking in {:?} for tests", actual_test_path);
 
+    let harness = Harness::new(config)?;
+    let policy = match &config.scoring {
+        Some(path) => ScoringPolicy::load(path)?,
+        None => ScoringPolicy::default(),
+    };
+    let reference_compiler_path = config
+        .cc0
+        .as_ref()
+        .map(|cc0| {
+            fs::canonicalize(cc0)
+                .with_context(|| format!("Reference compiler {cc0:?} does not exist"))
+        })
+        .transpose()?;
+
+    // Select tests before building the compiler, which can take a while, so a
+    // bad --filter or keep.txt is reported right away
+    let mut test_files = select_tests(collect_files(&actual_test_path)?, config.filter.as_deref())?;
+    if config.prune {
+        let keep_list = KeepList::read(&actual_test_path.join(KEEP_FILE))?;
+        let (selected, unmatched) = keep_list.select(&actual_test_path, test_files);
+        for entry in unmatched {
+            println!(
+                "{}",
+                format!("{KEEP_FILE} entry {entry:?} matched no tests").yellow()
+            );
+        }
+        test_files = selected;
+    }
+
     rayon::ThreadPoolBuilder::new()
         .num_threads(config.parallel.unwrap_or(1).try_into().unwrap())
         .build_global()
         .unwrap();
-    // Assume Make is in CWD
-    {
-        let mut make_cmd = Command::new("make");
-        if let Some(par) = config.parallel {
-            make_cmd.args(["-j", par.to_string().as_str()]);
-        }
+    reset_log_dir()?;
+    interrupt::install_handler()?;
 
-        let status = make_cmd.status()?;
-        if !status.success() {
-            bail!("Expected make to succeed but failed");
-        }
+    if let Some(reference) = &reference_compiler_path {
+        probe_reference(&harness, reference)?;
+    }
+
+    // Assume Make is in CWD
+    if !config.nomake {
+        build_compiler(config)?;
     }
 
     // Student compiler should be made and now exists in
     // CWD/bin
     //
-    let student_compiler_path = fs::canonicalize(Path::new("./bin/c0c"))?;
-    // let runtime_path = path::absolute(Path::new("../runtime"))?;
-
+    let student_compiler_path = Path::new("./bin/c0c");
     if !student_compiler_path.exists() {
         bail!("Expected ./bin/c0c to exist");
     }
+    let student_compiler_path = fs::canonicalize(student_compiler_path)?;
 
-    // This is the main business logic
-    let run_and_verify = |p: &PathBuf| -> Result<TestOutcome> {
-        let intended_result =
-            parser::get_test_result(p).with_context(|| format!("Test {p:?} failed to parse"))?;
+    let (tests, inapplicable) = select_applicable(parse_tests(
+        test_files,
+        config.relax,
+        &harness.compiler_args,
+    ));
+    for test in &inapplicable {
+        println!(
+            "{}",
+            format!(
+                "Skipping {}: no directive applies to these compiler args",
+                test.name(&actual_test_path)
+            )
+            .yellow()
+        );
+    }
+    let tests = select_by_safety(tests, config.safe_only, config.unsafe_only);
+    let tests = select_infloop(tests, config.allow_infloop_tests);
 
-        let tempdir = TempDir::new("c0_runner").unwrap();
-        let runtime_path = fs::canonicalize(Path::new("../runtime"))?;
+    let vetting = config
+        .static_analysis_dir
+        .as_ref()
+        .map(|dir| {
+            vet_tests(
+                &tests,
+                &actual_test_path,
+                Path::new(dir),
+                limit(config.limit_compile),
+            )
+        })
+        .transpose()?
+        .unwrap_or_default();
+
+    // Set once the reference compiler fails, which ends the run, so the tests
+    // still to go can be skipped
+    let reference_failed = AtomicBool::new(false);
+
+    // This is the main business logic
+    let run_and_verify = |test: &Test,
+                          log: &mut TestLog,
+                          differs_from_reference: &mut Option<String>|
+     -> Result<TestOutcome> {
+        if reference_failed.load(Ordering::Relaxed) {
+            bail!("Skipped after the reference compiler failed");
+        }
+        let p = &test.path;
+        let intended_result = match &test.directive {
+            Ok(directive) => directive,
+            Err(e) => return Err(TestFailure::MalformedTest(e.to_string()).into()),
+        };
         let test_name = p
             .file_name()
             .ok_or(anyhow!("Couldn't extract file name from p"))?;
-        let new_test_path = tempdir.path().join(test_name);
-        fs::copy(p, &new_test_path)?;
-        // Symlinks might be weird...
-        // symlink(p, &new_test_path)?;
-
-        // TODO: add user supported args
-        let compiler_output = Command::new(student_compiler_path.clone())
-            .arg("-ex86-64")
-            .arg(new_test_path.to_str().unwrap())
-            .output()
-            .with_context(|| "Student compiler failed")?;
-
-        if matches!(intended_result, TestResult::SourceError) {
-            return if !compiler_output.status.success() {
-                Ok(TestOutcome::Passed)
-            } else {
-                Err(TestFailure::CompileFailure)
-                    .with_context(|| String::from_utf8_lossy(&compiler_output.stdout).to_string())
-            };
-        }
-
-        if !compiler_output.status.success() {
-            bail!("Student compiler failed");
-        }
-
-        let out_path = tempdir.path().join("a.out");
-
-        // let platform_args = if cfg!(target_os = "macos") {
-        //     ["-target", "x86_64-apple-darwin"]
-        // } else {
-        //     ["-target", "x86_64-linux-gnu"]
-        // };
-
-        // We should now have a a.out output file
-        // TODO: handle linking
-        let linked_output = Command::new("gcc")
-            .args([
-                "-g",
-                "-fno-stack-protector",
-                "-fno-lto",
-                "-fno-asynchronous-unwind-tables",
-                "-target",
-                {
-                    cfg_if::cfg_if! {
-                        if #[cfg(target_os = "macos")] {
-                            "x86_64-apple-darwin"
-                        } else if #[cfg(target_os = "linux")] {
-                            "x86_64-linux-gnu"
-                        } else {
-                            compile_error!("unspported target os")
-                        }
-                    }
-                },
-                "-O0",
-                "-o",
-                out_path.to_str().unwrap(),
-                add_extension(&new_test_path, "s").to_str().unwrap(),
-                runtime_path.join("run411.c").to_str().unwrap(),
-            ])
-            .output()
-            .with_context(|| "GCC failed to link")?;
-
-        if !linked_output.status.success() {
-            bail!(
-                "Failed to link with: \n\t{}",
-                String::from_utf8_lossy(&compiler_output.stdout).to_string()
-            );
+
+        if let Some(original) = vetting.duplicates.get(p) {
+            let reason = format!("duplicates {original:?}");
+            if config.fail_duplicate_tests {
+                return Ok(failed(test_name, TestOutcome::FailedVetting(reason)));
+            }
+            if config.warn_duplicate_tests {
+                println!("{}", format!("{test_name:?} {reason}").yellow());
+            }
         }
 
-        // // spawn compiled process
-        // let mut child = command::new(out_path).output().unwrap();;
-
-        let mut child = Command::new(out_path).stdout(Stdio::piped()).spawn()?;
-        let run_timeout = Duration::from_secs(config.limit_run as u64);
-        let status_code = child.wait_timeout(run_timeout)?;
-
-        let execution_result: ProcessResult = match status_code {
-            Some(status) => {
-                if status.success() {
-                    let child_stdout = child.stdout.take().unwrap();
-                    let last_line =
-                        String::from_utf8(child_stdout.bytes().collect::<Result<Vec<_>, _>>()?)?
-                            .lines()
-                            .last()
-                            .ok_or(anyhow!("No output"))?
-                            .parse::<i32>()?;
-                    ProcessResult::Success(last_line)
-                } else {
-                    if let Some(exit_code) = status.code() {
-                        ProcessResult::Failure(exit_code)
-                    } else {
-                        match status.signal().unwrap() {
-                            libc::SIGABRT => ProcessResult::SignalAbort,
-                            libc::SIGFPE => ProcessResult::SigFpe,
-                            libc::SIGUSR2 => ProcessResult::SignalUsr2,
-                            other => ProcessResult::OtherSignal(other),
+        if let Some(report) = vetting.dodgy.get(p) {
+            let reason = format!("test is dodgy:\n\t{report}");
+            if config.fail_dodgy_tests {
+                return Ok(failed(test_name, TestOutcome::FailedVetting(reason)));
+            }
+            println!("{}", format!("{test_name:?} {reason}").yellow());
+        }
+
+        // Differential testing: a directive the reference compiler disagrees with
+        // says nothing about the student compiler
+        let reference_result = match &reference_compiler_path {
+            Some(reference) => {
+                let (workdir, reference_test_path) = stage_test(p, "c0_reference", config.debug)?;
+                if let Workdir::Kept(path) = &workdir {
+                    log.note(format!("reference artifacts kept in {path:?}"));
+                }
+                log.note(format!("##### reference compiler {reference:?} #####\n"));
+                let reference_result =
+                    match harness.execute(reference, &reference_test_path, intended_result, log) {
+                        Ok(reference_result) => reference_result,
+                        Err(e) => {
+                            reference_failed.store(true, Ordering::Relaxed);
+                            let command = harness.compiler_command(reference, &reference_test_path);
+                            return Err(TestFailure::ReferenceFailed {
+                                command: display_command(&command),
+                                error: format!("{e:#}"),
+                            }
+                            .into());
                         }
-                    }
+                    };
+                log.note(format!("reference result: {reference_result}\n"));
+                if !meets_directive(intended_result, &reference_result) {
+                    let outcome = TestOutcome::BadTest {
+                        expected: intended_result.clone(),
+                        reference: reference_result,
+                    };
+                    println!("{}", format!("{test_name:?} is a {outcome}").yellow());
+                    return Ok(outcome);
                 }
+                Some(reference_result)
             }
-            None => {
-                child.kill()?;
-                child.wait()?;
-                ProcessResult::Timeout
-            }
+            None => None,
         };
 
-        Ok(match (intended_result, execution_result) {
-            (TestResult::Ret(r), ProcessResult::Success(o)) => {
-                if r == o {
-                    println!("{}", format!("Test {test_name:?} passed").green());
-                    TestOutcome::Passed
-                } else {
-                    println!(
-                        "{}",
-                        format!("{test_name:?} failed: expected {r} got {o}.").red()
-                    );
-                    TestOutcome::Failed
-                }
-            }
-            (TestResult::Abort, ProcessResult::SignalAbort)
-            | (TestResult::MemError, ProcessResult::SignalUsr2)
-            | (TestResult::DivByZero, ProcessResult::SigFpe) => {
-                println!("{}", format!("Test {test_name:?} passed").green());
-                TestOutcome::Passed
+        let (workdir, new_test_path) = stage_test(p, "c0_runner", config.debug)?;
+        if let Workdir::Kept(path) = &workdir {
+            log.note(format!("artifacts kept in {path:?}"));
+        }
+        log.note(format!(
+            "##### student compiler {student_compiler_path:?} #####\n"
+        ));
+        let observed =
+            harness.execute(&student_compiler_path, &new_test_path, intended_result, log)?;
+        log.note(format!("result: {observed}"));
+
+        if let Some(reference_result) = &reference_result {
+            if observed != *reference_result {
+                let difference =
+                    format!("{observed}, but with the reference compiler {reference_result}");
+                println!(
+                    "{}",
+                    format!("{test_name:?} differs from reference: {difference}").yellow()
+                );
+                log.note(format!("differs from reference: {difference}"));
+                *differs_from_reference = Some(difference);
             }
-            (_, ProcessResult::Timeout) => {
-                println!("{}", format!("{test_name:?} timed out").yellow());
-                TestOutcome::TimedOut
+        }
+
+        if meets_directive(intended_result, &observed) {
+            println!("{}", format!("Test {test_name:?} passed").green());
+            return Ok(TestOutcome::Passed);
+        }
+
+        Ok(match observed {
+            Observed::TimedOut(phase) => timed_out(test_name, phase),
+            Observed::Ran(ProcessResult::Timeout | ProcessResult::CpuLimit) => {
+                timed_out(test_name, Phase::Run)
             }
-            // TODO: handle this case with logging
-            _ => TestOutcome::Failed,
+            observed => failed(
+                test_name,
+                TestOutcome::Failed {
+                    expected: intended_result.clone(),
+                    observed,
+                },
+            ),
         })
     };
 
-    let map_score = |p: &PathBuf, r: Result<TestOutcome>| -> f32 {
-        let test_name = p.file_name().unwrap();
-        match r {
-            Ok(TestOutcome::Passed) => {
-                println!("{}", format!("Test {test_name:?} passed").green());
-                1.0
+    let scores = process_files_parallel(&tests, |test| {
+        let mut log = TestLog::default();
+        log.note(format!("test: {:?}", test.path));
+        if let Ok(directive) = &test.directive {
+            log.note(format!("directive: {directive}"));
+        }
+        log.note("");
+
+        let mut differs_from_reference = None;
+        let outcome = run_and_verify(test, &mut log, &mut differs_from_reference);
+        match &outcome {
+            Ok(outcome) => log.note(format!("outcome: {outcome}")),
+            Err(e) if outcome_kind(&outcome) == OutcomeKind::Malformed => {
+                let test_name = test.path.file_name().unwrap();
+                println!("{}", format!("{test_name:?}: {e}").yellow());
+                log.note(format!("outcome: malformed\n{e}"));
             }
-            Ok(TestOutcome::TimedOut) => -0.1,
-            Ok(TestOutcome::Failed) => -1.0,
             Err(e) => {
+                let test_name = test.path.file_name().unwrap();
                 println!(
                     "{}",
-                    format!("{test_name:?} failed with error\n\t {e}").red()
+                    format!("{test_name:?} failed with error\n\t {e:#}").red()
                 );
-                -1.0
+                log.note(format!("outcome: error\n{e:#}"));
             }
         }
+
+        if let Err(e) = log.write(&actual_test_path, &test.path) {
+            println!("{}", format!("{e:#}").yellow());
+        }
+        (outcome, differs_from_reference)
+    })?;
+
+    // Scoring without the reference would make every test a bad test worth nothing
+    if let Some((test, e)) = tests.iter().zip(&scores).find_map(|(test, (outcome, _))| {
+        outcome
+            .as_ref()
+            .err()
+            .filter(|e| matches!(e.downcast_ref(), Some(TestFailure::ReferenceFailed { .. })))
+            .map(|e| (test, e))
+    }) {
+        bail!("{e} while grading {}", test.name(&actual_test_path));
+    }
+
+    let reports: Vec<_> = tests
+        .iter()
+        .zip(&scores)
+        .map(|(test, (outcome, differs_from_reference))| {
+            let directive = test.directive.as_ref().ok();
+            let kind = outcome_kind(outcome);
+            TestReport {
+                name: test.name(&actual_test_path),
+                directive: directive.map(TestResult::to_string),
+                passed: kind == OutcomeKind::Passed,
+                outcome: match outcome {
+                    Ok(outcome) => outcome.to_string(),
+                    Err(e) if kind == OutcomeKind::Malformed => e.to_string(),
+                    Err(e) => format!("error: {e:#}"),
+                },
+                differs_from_reference: differs_from_reference.clone(),
+                score: policy.points(kind, directive),
+                max_score: policy.max_points(kind, directive),
+            }
+        })
+        .collect();
+
+    let mut summary = String::new();
+    for report in &reports {
+        summary.push_str(&format!("{}: {}\n", report.name, report.outcome));
+        if let Some(difference) = &report.differs_from_reference {
+            summary.push_str(&format!("\tdiffers from reference: {difference}\n"));
+        }
+    }
+
+    let mut bench_summary = String::new();
+    let (bench, performance) = if config.bench {
+        let passed: Vec<_> = tests
+            .iter()
+            .zip(&scores)
+            .filter(|(test, (outcome, _))| {
+                matches!(outcome, Ok(TestOutcome::Passed))
+                    && matches!(test.directive, Ok(TestResult::Ret(_)))
+            })
+            .map(|(test, _)| test)
+            .collect();
+        match &reference_compiler_path {
+            Some(_) => println!(
+                "Benchmarking {} tests against the reference compiler at {}",
+                passed.len(),
+                REFERENCE_LEVELS.join(" and ")
+            ),
+            None => println!("Benchmarking {} tests", passed.len()),
+        }
+
+        let (bench, performance) = bench_tests(
+            &harness,
+            &student_compiler_path,
+            reference_compiler_path.as_deref(),
+            &passed,
+            &actual_test_path,
+            &policy.performance,
+        );
+        let table = format_table(&bench);
+        print!("{table}");
+        bench_summary.push_str(&format!("\n{table}"));
+        if let Some(performance) = &performance {
+            let table = format_speedups(performance);
+            print!("{table}");
+            bench_summary.push_str(&format!("\n{table}"));
+        }
+
+        (bench, performance)
+    } else {
+        (Vec::new(), None)
     };
 
-    let scores = process_files_parallel(actual_test_path, run_and_verify)?;
+    let mut final_score = FinalScore::default();
+    let mut lab_points: BTreeMap<String, f32> = BTreeMap::new();
+    for ((test, (outcome, _)), report) in tests.iter().zip(&scores).zip(&reports) {
+        if let Ok(TestOutcome::TimedOut(phase)) = outcome {
+            *final_score.timeouts.entry(*phase).or_default() += 1;
+        }
+        let kind = outcome_kind(outcome);
+        match kind {
+            OutcomeKind::Passed => final_score.passed += 1,
+            OutcomeKind::Failed => final_score.failed += 1,
+            // Counted by phase above
+            OutcomeKind::Timeout => {}
+            OutcomeKind::BadTest => final_score.bad_test += 1,
+            OutcomeKind::Malformed => final_score.malformed += 1,
+        }
 
-    let final_score = scores.iter().fold(FinalScore::default(), |mut acc, e| {
-        match e {
-            Ok(TestOutcome::Passed) => acc.passed += 1,
-            Ok(TestOutcome::TimedOut) => acc.timeout += 1,
-            Ok(TestOutcome::Failed) | _ => acc.failed += 1,
-        };
+        *final_score.points.entry(kind.name()).or_default() += report.score;
+        let lab = test_extension(&test.path).unwrap_or_default().to_string();
+        *lab_points.entry(lab).or_default() += report.score;
+    }
+    if let Some(performance) = &performance {
+        final_score.points.insert("performance", performance.points);
+        lab_points.insert("performance".to_string(), performance.points);
+    }
+    (final_score.labs, final_score.score) = policy.total(lab_points);
 
-        acc
-    });
+    summary.push_str(&format!("\n{final_score}\n"));
+    summary.push_str(&bench_summary);
+
+    write_summary(&summary)?;
+
+    if config.nolog {
+        remove_log_dir()?;
+    } else {
+        println!("Logs written to {LOG_DIR}");
+    }
+
+    Ok(RunResults {
+        score: final_score,
+        tests: reports,
+        bench,
+        performance,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn return_values() {
+        assert_eq!(parse_return_value(b"42\n"), ProcessResult::Success(42));
+        assert_eq!(parse_return_value(b"debug\n-7"), ProcessResult::Success(-7));
+        // Only the last line has to be UTF-8
+        assert_eq!(
+            parse_return_value(b"\xff\xfe garbage\n3\n"),
+            ProcessResult::Success(3)
+        );
+    }
 
-    Ok(final_score)
+    #[test]
+    fn bad_output() {
+        for (stdout, expected) in [
+            (&b""[..], &b""[..]),
+            (b"\n", b"\n"),
+            (b"1\nnot a number\n", b"1\nnot a number\n"),
+            (b"12abc", b"12abc"),
+            (b"3\n\xff\n", b"3\n\xff\n"),
+        ] {
+            assert_eq!(
+                parse_return_value(stdout),
+                ProcessResult::BadOutput {
+                    output: expected.to_vec(),
+                    truncated: false,
+                },
+                "{:?}",
+                stdout.escape_ascii().to_string()
+            );
+        }
+        assert_eq!(
+            parse_return_value(b"").to_string(),
+            "exited without printing a return value"
+        );
+
+        let mut stdout = vec![b'x'; REPORTED_OUTPUT];
+        stdout.extend_from_slice(b"tail");
+        match parse_return_value(&stdout) {
+            ProcessResult::BadOutput { output, truncated } => {
+                assert!(truncated);
+                assert_eq!(output.len(), REPORTED_OUTPUT);
+                assert!(output.ends_with(b"tail"));
+            }
+            other => panic!("expected bad output, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn compiler_exits() {
+        // Raw wait statuses: the exit code is in the second byte, a signal in the first
+        let exit = |code: i32| Some(ExitStatus::from_raw(code << 8));
+        let signal = |signal: i32| Some(ExitStatus::from_raw(signal));
+
+        let cases = [
+            (exit(0), None),
+            (exit(1), Some(Observed::Rejected)),
+            (
+                exit(101),
+                Some(Observed::CompilerFailed(ProcessResult::Failure(101))),
+            ),
+            (
+                signal(libc::SIGSEGV),
+                Some(Observed::CompilerFailed(ProcessResult::SegFault)),
+            ),
+            (None, Some(Observed::TimedOut(Phase::Compile))),
+        ];
+        for (status, expected) in cases {
+            assert_eq!(
+                classify_compiler_exit(status, Phase::Compile),
+                expected,
+                "{status:?}"
+            );
+        }
+
+        let segfault = classify_compiler_exit(signal(libc::SIGSEGV), Phase::Compile).unwrap();
+        assert!(segfault.to_string().contains("SIGSEGV"), "{segfault}");
+    }
+
+    #[test]
+    fn directives_met() {
+        use TestResult::*;
+        let cases = [
+            (SourceError, Observed::Rejected, true),
+            (SourceError, Observed::Accepted, false),
+            (
+                SourceError,
+                Observed::CompilerFailed(ProcessResult::Failure(101)),
+                false,
+            ),
+            (
+                SourceError,
+                Observed::CompilerFailed(ProcessResult::SegFault),
+                false,
+            ),
+            (SourceError, Observed::TimedOut(Phase::Compile), false),
+            (SourceError, Observed::TimedOut(Phase::Typecheck), false),
+            (TypeCheck, Observed::Accepted, true),
+            (TypeCheck, Observed::Rejected, false),
+            (Ret(3), Observed::Ran(ProcessResult::Success(3)), true),
+            (Ret(3), Observed::Ran(ProcessResult::Success(4)), false),
+            (Abort, Observed::Ran(ProcessResult::SignalAbort), true),
+            (DivByZero, Observed::Ran(ProcessResult::SegFault), false),
+            (InfLoop, Observed::Ran(ProcessResult::Timeout), true),
+        ];
+        for (expected, observed, met) in cases {
+            assert_eq!(
+                meets_directive(&expected, &observed),
+                met,
+                "`{expected}` with {observed}"
+            );
+        }
+    }
 }
@@ -15,7 +15,7 @@ where
 }
 
 /// Collects all files from a directory recursively
-fn collect_files(dir: &Path) -> Result<Vec<PathBuf>> {
+pub fn collect_files(dir: &Path) -> Result<Vec<PathBuf>> {
     let mut files = Vec::new();
 
     if !dir.is_dir() {
@@ -41,15 +41,12 @@ fn collect_files(dir: &Path) -> Result<Vec<PathBuf>> {
 }
 
 /// Splits files into chunks and processes them in parallel
-pub fn process_files_parallel<F, R, P>(dir: P, process_file: F) -> Result<Vec<R>>
+pub fn process_files_parallel<T, F, R>(files: &[T], process_file: F) -> Result<Vec<R>>
 where
-    F: Fn(&PathBuf) -> R + Send + Sync,
+    T: Sync,
+    F: Fn(&T) -> R + Send + Sync,
     R: Send + Sync,
-    P: AsRef<Path>,
 {
-    // Collect all files
-    let files = collect_files(dir.as_ref())?;
-
     let results: Vec<_> = files.par_iter().map(process_file).collect();
 
     Ok(results)
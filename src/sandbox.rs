@@ -0,0 +1,237 @@
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::process::Command;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Context, Error, Result};
+use colored::Colorize;
+
+/// Whether --sandbox isolates the student compiler and test programs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SandboxMode {
+    /// Run everything with the grader's privileges
+    Off,
+    /// Sandbox if the kernel allows it, otherwise warn and run unsandboxed
+    Auto,
+    /// Refuse to grade without a sandbox
+    Required,
+}
+
+impl FromStr for SandboxMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(SandboxMode::Off),
+            "auto" => Ok(SandboxMode::Auto),
+            "required" => Ok(SandboxMode::Required),
+            other => bail!("Expected sandbox mode off | auto | required instead got: {other}"),
+        }
+    }
+}
+
+/// Runs commands in new user, mount and network namespaces. The whole
+/// filesystem is read-only except for one directory, and there is no network.
+#[derive(Debug, Clone)]
+pub struct Sandbox {
+    uid_map: Vec<u8>,
+    gid_map: Vec<u8>,
+}
+
+impl Sandbox {
+    /// Checks that namespaces can be created, returning `None` when the sandbox is
+    /// off or unavailable in `Auto` mode
+    pub fn new(mode: SandboxMode) -> Result<Option<Self>> {
+        if mode == SandboxMode::Off {
+            return Ok(None);
+        }
+
+        // Map the grader's ids to themselves, except that root becomes nobody so
+        // the sandboxed process can't just remount everything writable
+        let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        let inside = |id: u32| if id == 0 { 65534 } else { id };
+        let sandbox = Self {
+            uid_map: format!("{} {uid} 1", inside(uid)).into_bytes(),
+            gid_map: format!("{} {gid} 1", inside(gid)).into_bytes(),
+        };
+
+        match sandbox.probe() {
+            Ok(()) => Ok(Some(sandbox)),
+            Err(e) => {
+                let diagnostic = format!(
+                    "Can't sandbox student code: {e:#}\n\
+                     \tThe sandbox needs Linux user namespaces (kernel.unprivileged_userns_clone, \
+                     or root in containers without seccomp restrictions) and mount_setattr (Linux 5.12+)"
+                );
+                if mode == SandboxMode::Required {
+                    bail!(
+                        "{diagnostic}\n\tPass --sandbox auto or --sandbox off to grade without it"
+                    );
+                }
+                println!(
+                    "{}",
+                    format!("{diagnostic}\n\tGrading without a sandbox").yellow()
+                );
+                Ok(None)
+            }
+        }
+    }
+
+    /// Runs a trivial command in the sandbox so a missing kernel feature is
+    /// reported once up front rather than as a failure of every test
+    fn probe(&self) -> Result<()> {
+        let mut cmd = Command::new("true");
+        self.apply_to(&mut cmd, &std::env::temp_dir())?;
+        let status = cmd.status()?;
+        if !status.success() {
+            bail!("sandboxed `true` exited with {status}");
+        }
+        Ok(())
+    }
+
+    /// Makes `cmd` enter the sandbox before it execs, with `writable` as the one
+    /// directory it can write to. `TMPDIR` is pointed there too.
+    pub fn apply_to(&self, cmd: &mut Command, writable: &Path) -> Result<()> {
+        let writable_dir = CString::new(writable.as_os_str().as_bytes())
+            .map_err(|_| anyhow!("Path {writable:?} can't be passed to mount"))?;
+        let uid_map = self.uid_map.clone();
+        let gid_map = self.gid_map.clone();
+
+        cmd.env("TMPDIR", writable);
+        imp::install(cmd, uid_map, gid_map, writable_dir)
+            .with_context(|| format!("Failed to sandbox {:?}", cmd.get_program()))
+    }
+}
+
+cfg_if::cfg_if! {
+    if #[cfg(target_os = "linux")] {
+        mod imp {
+            use std::ffi::{CStr, CString};
+            use std::io;
+            use std::os::unix::process::CommandExt;
+            use std::process::Command;
+            use std::ptr;
+
+            use anyhow::Result;
+
+            const MOUNT_ATTR_RDONLY: u64 = 0x1;
+
+            /// `struct mount_attr` from linux/mount.h, which libc doesn't define
+            #[repr(C)]
+            struct MountAttr {
+                attr_set: u64,
+                attr_clr: u64,
+                propagation: u64,
+                userns_fd: u64,
+            }
+
+            pub fn install(
+                cmd: &mut Command,
+                uid_map: Vec<u8>,
+                gid_map: Vec<u8>,
+                writable: CString,
+            ) -> Result<()> {
+                // SAFETY: everything the hook needs is allocated before the fork and
+                // it only makes raw syscalls, which are async-signal-safe
+                unsafe {
+                    cmd.pre_exec(move || enter(&uid_map, &gid_map, &writable));
+                }
+                Ok(())
+            }
+
+            fn enter(uid_map: &[u8], gid_map: &[u8], writable: &CStr) -> io::Result<()> {
+                unsafe {
+                    check(libc::unshare(
+                        libc::CLONE_NEWUSER | libc::CLONE_NEWNS | libc::CLONE_NEWNET,
+                    ))?;
+                }
+
+                // An unprivileged process has to give up setgroups before it can map its gid
+                write_file(c"/proc/self/setgroups", b"deny")?;
+                write_file(c"/proc/self/uid_map", uid_map)?;
+                write_file(c"/proc/self/gid_map", gid_map)?;
+
+                unsafe {
+                    // Keep the changes below from propagating back to the grader
+                    check(libc::mount(
+                        ptr::null(),
+                        c"/".as_ptr(),
+                        ptr::null(),
+                        libc::MS_REC | libc::MS_PRIVATE,
+                        ptr::null(),
+                    ))?;
+                }
+                set_readonly(c"/", true)?;
+
+                unsafe {
+                    check(libc::mount(
+                        writable.as_ptr(),
+                        writable.as_ptr(),
+                        ptr::null(),
+                        libc::MS_BIND | libc::MS_REC,
+                        ptr::null(),
+                    ))?;
+                }
+                set_readonly(writable, false)
+            }
+
+            /// Sets or clears read-only on the mount at `path` and every mount below it
+            fn set_readonly(path: &CStr, readonly: bool) -> io::Result<()> {
+                let attr = MountAttr {
+                    attr_set: if readonly { MOUNT_ATTR_RDONLY } else { 0 },
+                    attr_clr: if readonly { 0 } else { MOUNT_ATTR_RDONLY },
+                    propagation: 0,
+                    userns_fd: 0,
+                };
+                let ret = unsafe {
+                    libc::syscall(
+                        libc::SYS_mount_setattr,
+                        libc::AT_FDCWD,
+                        path.as_ptr(),
+                        libc::AT_RECURSIVE as libc::c_uint,
+                        &attr as *const MountAttr,
+                        std::mem::size_of::<MountAttr>(),
+                    )
+                };
+                check(ret as libc::c_int)
+            }
+
+            fn write_file(path: &CStr, contents: &[u8]) -> io::Result<()> {
+                unsafe {
+                    let fd = libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+                    check(fd)?;
+                    let written = libc::write(fd, contents.as_ptr().cast(), contents.len());
+                    libc::close(fd);
+                    if written < 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            }
+
+            fn check(ret: libc::c_int) -> io::Result<()> {
+                if ret < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            }
+        }
+    } else {
+        mod imp {
+            use std::ffi::CString;
+            use std::process::Command;
+
+            use anyhow::{bail, Result};
+
+            pub fn install(
+                _cmd: &mut Command,
+                _uid_map: Vec<u8>,
+                _gid_map: Vec<u8>,
+                _writable: CString,
+            ) -> Result<()> {
+                bail!("sandboxing needs Linux namespaces")
+            }
+        }
+    }
+}
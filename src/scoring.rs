@@ -0,0 +1,279 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::parser::TestResult;
+
+/// The scoring categories a graded test falls into
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutcomeKind {
+    Passed,
+    Failed,
+    Timeout,
+    BadTest,
+    Malformed,
+}
+
+impl OutcomeKind {
+    /// The name of the outcome in the serialized points breakdown, the same as
+    /// its weight in [`ScoringPolicy`]
+    pub fn name(&self) -> &'static str {
+        match self {
+            OutcomeKind::Passed => "passed",
+            OutcomeKind::Failed => "failed",
+            OutcomeKind::Timeout => "timeout",
+            OutcomeKind::BadTest => "bad_test",
+            OutcomeKind::Malformed => "malformed",
+        }
+    }
+}
+
+/// How test outcomes turn into points, loaded from a JSON file with --scoring
+///
+/// A test is worth its outcome weight times its directive weight. Tests are
+/// summed per lab extension and capped by `lab_caps`, then the labs are summed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScoringPolicy {
+    pub passed: f32,
+    pub failed: f32,
+    pub timeout: f32,
+    pub bad_test: f32,
+    /// Tests whose directive couldn't be parsed
+    pub malformed: f32,
+    /// Never report a total below zero
+    pub floor_at_zero: bool,
+    /// Multiplier by directive keyword (`return`, `error`, ...), 1.0 if absent
+    pub directive_weights: BTreeMap<String, f32>,
+    /// Most points a lab extension (`l1`, `l3`, ...) can contribute, uncapped if absent.
+    /// Performance points count as the `performance` lab.
+    pub lab_caps: BTreeMap<String, f32>,
+    pub performance: PerformancePolicy,
+}
+
+impl Default for ScoringPolicy {
+    fn default() -> Self {
+        Self {
+            passed: 1.0,
+            failed: -1.0,
+            timeout: -0.1,
+            bad_test: 0.0,
+            malformed: 0.0,
+            floor_at_zero: true,
+            directive_weights: BTreeMap::new(),
+            lab_caps: BTreeMap::new(),
+            performance: PerformancePolicy::default(),
+        }
+    }
+}
+
+impl ScoringPolicy {
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read scoring policy {path:?}"))?;
+        let policy: Self = serde_json::from_str(&contents)
+            .with_context(|| format!("Failed to parse scoring policy {path:?}"))?;
+        policy
+            .performance
+            .validate()
+            .with_context(|| format!("Invalid performance curve in {path:?}"))?;
+        Ok(policy)
+    }
+
+    fn outcome_weight(&self, outcome: OutcomeKind) -> f32 {
+        match outcome {
+            OutcomeKind::Passed => self.passed,
+            OutcomeKind::Failed => self.failed,
+            OutcomeKind::Timeout => self.timeout,
+            OutcomeKind::BadTest => self.bad_test,
+            OutcomeKind::Malformed => self.malformed,
+        }
+    }
+
+    fn directive_weight(&self, directive: Option<&TestResult>) -> f32 {
+        directive
+            .and_then(|directive| self.directive_weights.get(directive.keyword()))
+            .copied()
+            .unwrap_or(1.0)
+    }
+
+    /// Points earned by a single test
+    pub fn points(&self, outcome: OutcomeKind, directive: Option<&TestResult>) -> f32 {
+        self.outcome_weight(outcome) * self.directive_weight(directive)
+    }
+
+    /// Points a single test could have earned. Bad and malformed tests are the
+    /// course's fault rather than the student's, so they aren't worth anything.
+    pub fn max_points(&self, outcome: OutcomeKind, directive: Option<&TestResult>) -> f32 {
+        match outcome {
+            OutcomeKind::BadTest | OutcomeKind::Malformed => 0.0,
+            _ => self.points(OutcomeKind::Passed, directive),
+        }
+    }
+
+    /// Applies the lab caps and floor to per-lab point totals, returning the
+    /// capped per-lab totals and the final score
+    pub fn total(&self, labs: BTreeMap<String, f32>) -> (BTreeMap<String, f32>, f32) {
+        let labs: BTreeMap<_, _> = labs
+            .into_iter()
+            .map(|(lab, points)| {
+                let cap = self.lab_caps.get(&lab).copied().unwrap_or(f32::INFINITY);
+                (lab, points.min(cap))
+            })
+            .collect();
+
+        // Summing no labs with `sum` gives -0.0, which would be reported as `-0`
+        let total = labs.values().fold(0.0, |total, points| total + points);
+        let total = if self.floor_at_zero {
+            total.max(0.0)
+        } else {
+            total
+        };
+
+        (labs, total)
+    }
+}
+
+/// Optimization levels the reference compiler is benchmarked at
+pub const REFERENCE_LEVELS: &[&str] = &["-O0", "-O1"];
+
+/// How --bench speedups over the reference compiler (--cc0) turn into points
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PerformancePolicy {
+    /// Which of `REFERENCE_LEVELS` the speedup that gets scored is measured against
+    pub against: String,
+    /// `[speedup, points]` pairs in increasing speedup order. Points are linearly
+    /// interpolated between them and flat past either end.
+    pub curve: Vec<[f64; 2]>,
+}
+
+impl Default for PerformancePolicy {
+    fn default() -> Self {
+        Self {
+            against: "-O1".to_string(),
+            // Nothing for code twice as slow as the reference, full marks for matching it
+            curve: vec![[0.5, 0.0], [1.0, 1.0]],
+        }
+    }
+}
+
+impl PerformancePolicy {
+    fn validate(&self) -> Result<()> {
+        if !REFERENCE_LEVELS.contains(&self.against.as_str()) {
+            bail!(
+                "Expected against to be one of {} instead got: {}",
+                REFERENCE_LEVELS.join(" | "),
+                self.against
+            );
+        }
+        if self.curve.is_empty() {
+            bail!("Expected at least one point on the curve");
+        }
+        if self.curve.windows(2).any(|pair| pair[0][0] >= pair[1][0]) {
+            bail!("Expected curve speedups to be strictly increasing");
+        }
+        Ok(())
+    }
+
+    /// Points earned by a test whose program is `speedup` times as fast as the
+    /// reference compiler's
+    pub fn points(&self, speedup: f64) -> f32 {
+        let (first, last) = (self.curve[0], self.curve[self.curve.len() - 1]);
+        if speedup <= first[0] {
+            return first[1] as f32;
+        }
+
+        for pair in self.curve.windows(2) {
+            let ([x0, y0], [x1, y1]) = (pair[0], pair[1]);
+            if speedup <= x1 {
+                return (y0 + (y1 - y0) * (speedup - x0) / (x1 - x0)) as f32;
+            }
+        }
+
+        last[1] as f32
+    }
+
+    /// Most points a single test can earn
+    pub fn max_points(&self) -> f32 {
+        self.curve
+            .iter()
+            .map(|&[_, points]| points as f32)
+            .fold(f32::NEG_INFINITY, f32::max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn caps_and_floor() {
+        let policy: ScoringPolicy = serde_json::from_str(
+            r#"{ "lab_caps": { "l1": 2.0 }, "directive_weights": { "error": 0.5 } }"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            policy.points(OutcomeKind::Failed, Some(&TestResult::SourceError)),
+            -0.5
+        );
+        assert_eq!(policy.points(OutcomeKind::Timeout, None), -0.1);
+
+        let labs = BTreeMap::from([("l1".to_string(), 5.0), ("l2".to_string(), 1.0)]);
+        assert_eq!(policy.total(labs).1, 3.0);
+
+        // More failures than passes used to underflow
+        let labs = BTreeMap::from([("l1".to_string(), -4.0)]);
+        assert_eq!(policy.total(labs).1, 0.0);
+
+        // No tests at all
+        let empty = policy.total(BTreeMap::new()).1;
+        assert!(empty == 0.0 && empty.is_sign_positive(), "{empty}");
+    }
+
+    #[test]
+    fn performance_curve() {
+        let policy = PerformancePolicy {
+            against: "-O0".to_string(),
+            curve: vec![[1.0, 0.0], [2.0, 2.0], [4.0, 3.0]],
+        };
+        assert!(policy.validate().is_ok());
+
+        assert_eq!(policy.points(0.5), 0.0);
+        assert_eq!(policy.points(1.5), 1.0);
+        assert_eq!(policy.points(3.0), 2.5);
+        assert_eq!(policy.points(10.0), 3.0);
+        assert_eq!(policy.max_points(), 3.0);
+
+        let unordered = PerformancePolicy {
+            curve: vec![[2.0, 1.0], [1.0, 0.0]],
+            ..policy.clone()
+        };
+        assert!(unordered.validate().is_err());
+        let unknown_level = PerformancePolicy {
+            against: "-O3".to_string(),
+            ..policy
+        };
+        assert!(unknown_level.validate().is_err());
+    }
+
+    #[test]
+    fn outcome_names_match_policy_keys() {
+        let policy = serde_json::to_value(ScoringPolicy::default()).unwrap();
+        for outcome in [
+            OutcomeKind::Passed,
+            OutcomeKind::Failed,
+            OutcomeKind::Timeout,
+            OutcomeKind::BadTest,
+            OutcomeKind::Malformed,
+        ] {
+            assert!(policy.get(outcome.name()).is_some(), "{}", outcome.name());
+            assert_eq!(serde_json::to_value(outcome).unwrap(), outcome.name());
+        }
+    }
+}
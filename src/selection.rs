@@ -0,0 +1,267 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use rayon::prelude::*;
+
+use crate::parser::{self, ParseError, TestResult};
+
+/// Name of the file listing which tests --prune keeps
+pub const KEEP_FILE: &str = "keep.txt";
+
+/// Extensions of C0 sources that are tests. Headers (`.h0`), READMEs and
+/// anything else in the test directory are skipped.
+pub const TEST_EXTENSIONS: &[&str] = &["l1", "l2", "l3", "l4", "l5", "l6", "c0"];
+
+/// Returns the lab extension of `path` if it is a test
+pub fn test_extension(path: &Path) -> Option<&str> {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .filter(|ext| TEST_EXTENSIONS.contains(ext))
+}
+
+/// Keeps only the tests in `files`, narrowed to a single extension by `filter`
+/// (given as `l3` or `.l3`)
+pub fn select_tests(files: Vec<PathBuf>, filter: Option<&str>) -> Result<Vec<PathBuf>> {
+    let filter = filter
+        .map(|filter| {
+            let ext = filter.trim_start_matches('.');
+            if !TEST_EXTENSIONS.contains(&ext) {
+                bail!(
+                    "Expected --filter to be one of {} instead got: {filter}",
+                    TEST_EXTENSIONS.join(" | ")
+                );
+            }
+            Ok(ext)
+        })
+        .transpose()?;
+
+    Ok(files
+        .into_iter()
+        .filter(|file| match (test_extension(file), filter) {
+            (Some(ext), Some(filter)) => ext == filter,
+            (Some(_), None) => true,
+            (None, _) => false,
+        })
+        .collect())
+}
+
+/// A test file along with its parsed directive
+#[derive(Debug)]
+pub struct Test {
+    pub path: PathBuf,
+    pub directive: Result<TestResult, ParseError>,
+}
+
+impl Test {
+    /// Path of the test relative to the test directory `root`, which is how
+    /// tests are named in reports
+    pub fn name(&self, root: &Path) -> String {
+        self.path
+            .strip_prefix(root)
+            .unwrap_or(&self.path)
+            .display()
+            .to_string()
+    }
+}
+
+/// Parses the directive of every test up front so selection can look at them.
+/// `relax` and the compiler `args` in use are passed on to
+/// [`parser::get_test_result`].
+pub fn parse_tests(files: Vec<PathBuf>, relax: bool, args: &[String]) -> Vec<Test> {
+    files
+        .into_par_iter()
+        .map(|path| {
+            let directive = parser::get_test_result(&path, relax, args);
+            Test { path, directive }
+        })
+        .collect()
+}
+
+/// Applies --safe-only and --unsafe-only. Tests whose directive failed to parse
+/// are always kept so the failure gets reported.
+pub fn select_by_safety(tests: Vec<Test>, safe_only: bool, unsafe_only: bool) -> Vec<Test> {
+    tests
+        .into_iter()
+        .filter(|test| match &test.directive {
+            Ok(directive) if safe_only => !directive.is_unsafe(),
+            Ok(directive) if unsafe_only => directive.is_unsafe(),
+            _ => true,
+        })
+        .collect()
+}
+
+/// Drops `//test infloop` tests unless --allow-infloop-tests was given
+pub fn select_infloop(tests: Vec<Test>, allow_infloop: bool) -> Vec<Test> {
+    tests
+        .into_iter()
+        .filter(|test| allow_infloop || !matches!(test.directive, Ok(TestResult::InfLoop)))
+        .collect()
+}
+
+/// Drops tests with only conditional directives, none of which apply to the
+/// compiler args in use, returning them separately so they can be mentioned
+pub fn select_applicable(tests: Vec<Test>) -> (Vec<Test>, Vec<Test>) {
+    tests
+        .into_iter()
+        .partition(|test| !matches!(test.directive, Err(ParseError::NoMatchingDirective(_))))
+}
+
+/// Test selection read from keep.txt
+///
+/// Each non-blank line is either a plain test name or a glob pattern using `*`
+/// and `?`, matched against both the file name and the path relative to the
+/// test directory. Everything after a `#` is a comment.
+#[derive(Debug, Default)]
+pub struct KeepList {
+    entries: Vec<String>,
+}
+
+impl KeepList {
+    pub fn parse(contents: &str) -> Self {
+        let entries = contents
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or_default().trim())
+            .filter(|line| !line.is_empty())
+            .map(String::from)
+            .collect();
+
+        Self { entries }
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        let contents =
+            fs::read_to_string(path).with_context(|| format!("Failed to read {path:?}"))?;
+        Ok(Self::parse(&contents))
+    }
+
+    /// Keeps the files in `files` (found under `root`) matched by some entry,
+    /// returning them along with the entries that matched nothing
+    pub fn select(&self, root: &Path, files: Vec<PathBuf>) -> (Vec<PathBuf>, Vec<&str>) {
+        let mut used = vec![false; self.entries.len()];
+
+        let selected = files
+            .into_iter()
+            .filter(|file| {
+                let name = file
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+                let relative = file
+                    .strip_prefix(root)
+                    .unwrap_or(file)
+                    .to_string_lossy()
+                    .into_owned();
+
+                let mut kept = false;
+                for (entry, used) in self.entries.iter().zip(used.iter_mut()) {
+                    if glob_match(entry, &name) || glob_match(entry, &relative) {
+                        *used = true;
+                        kept = true;
+                    }
+                }
+                kept
+            })
+            .collect();
+
+        let unmatched = self
+            .entries
+            .iter()
+            .zip(used)
+            .filter(|(_, used)| !used)
+            .map(|(entry, _)| entry.as_str())
+            .collect();
+
+        (selected, unmatched)
+    }
+}
+
+/// Matches `text` against a pattern where `*` is any run of characters and `?`
+/// is any single character
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` seen and the text position it was tried at
+    let mut backtrack = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, star_t)) => {
+                    p = star + 1;
+                    t = star_t + 1;
+                    backtrack = Some((star, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_patterns() {
+        assert!(glob_match("foo.l1", "foo.l1"));
+        assert!(glob_match("*.l1", "foo.l1"));
+        assert!(glob_match("f?o*", "foo.l1"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("*.l2", "foo.l1"));
+        assert!(!glob_match("foo", "foo.l1"));
+    }
+
+    #[test]
+    fn keep_list_reports_unmatched_entries() {
+        let keep = KeepList::parse("# header\nfoo.l1\nsub/*.l2 # nested\n\nmissing.l3\n");
+        let root = Path::new("/tests");
+        let files = vec![
+            root.join("foo.l1"),
+            root.join("bar.l1"),
+            root.join("sub/baz.l2"),
+        ];
+
+        let (selected, unmatched) = keep.select(root, files);
+        assert_eq!(selected, vec![root.join("foo.l1"), root.join("sub/baz.l2")]);
+        assert_eq!(unmatched, vec!["missing.l3"]);
+    }
+
+    #[test]
+    fn inapplicable_tests_are_dropped() {
+        let test = |name: &str, directive| Test {
+            path: PathBuf::from(name),
+            directive,
+        };
+        let tests = vec![
+            test("ret.l1", Ok(TestResult::Ret(0))),
+            test(
+                "unsafe.l1",
+                Err(ParseError::NoMatchingDirective(String::new())),
+            ),
+            test("empty.l1", Err(ParseError::EmptyFile)),
+        ];
+
+        let (kept, dropped) = select_applicable(tests);
+        let names = |tests: &[Test]| {
+            tests
+                .iter()
+                .map(|t| t.name(Path::new("")))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(&kept), vec!["ret.l1", "empty.l1"]);
+        assert_eq!(names(&dropped), vec!["unsafe.l1"]);
+    }
+}
@@ -0,0 +1,75 @@
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::process::{describe_status, TimedOutput};
+
+/// Directory in the CWD that build, per-test and summary logs are written to
+pub const LOG_DIR: &str = "grader-logs";
+
+/// Name of the summary log inside `LOG_DIR`
+pub const SUMMARY_LOG: &str = "summary.log";
+
+/// Everything that happened while grading a single test, written to
+/// `LOG_DIR/<test>.log` once the test is done
+#[derive(Debug, Default)]
+pub struct TestLog {
+    contents: String,
+}
+
+impl TestLog {
+    /// Records a subprocess run as part of `step`, e.g. "compile" or "run"
+    pub fn command(&mut self, step: &str, command: &str, output: &TimedOutput) {
+        let contents = &mut self.contents;
+        let _ = writeln!(contents, "== {step} ==");
+        let _ = writeln!(contents, "$ {command}");
+        let _ = writeln!(contents, "status: {}", describe_status(output.status));
+        let _ = writeln!(contents, "time: {:.3}s", output.elapsed.as_secs_f64());
+        for (name, bytes) in [("stdout", &output.stdout), ("stderr", &output.stderr)] {
+            if !bytes.is_empty() {
+                let _ = writeln!(contents, "-- {name} --");
+                let _ = writeln!(contents, "{}", String::from_utf8_lossy(bytes).trim_end());
+            }
+        }
+        contents.push('\n');
+    }
+
+    pub fn note(&mut self, line: impl AsRef<str>) {
+        let _ = writeln!(self.contents, "{}", line.as_ref());
+    }
+
+    /// Writes the log for the test at `test`, found under `test_root`
+    pub fn write(&self, test_root: &Path, test: &Path) -> Result<PathBuf> {
+        let relative = test.strip_prefix(test_root).unwrap_or(test);
+        let mut path = Path::new(LOG_DIR).join(relative).into_os_string();
+        path.push(".log");
+        let path = PathBuf::from(path);
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&path, &self.contents).with_context(|| format!("Failed to write {path:?}"))?;
+
+        Ok(path)
+    }
+}
+
+pub fn write_summary(summary: &str) -> Result<()> {
+    let path = Path::new(LOG_DIR).join(SUMMARY_LOG);
+    fs::write(&path, summary).with_context(|| format!("Failed to write {path:?}"))
+}
+
+/// Clears out logs from a previous run
+pub fn reset_log_dir() -> Result<()> {
+    if Path::new(LOG_DIR).exists() {
+        fs::remove_dir_all(LOG_DIR).with_context(|| format!("Failed to remove {LOG_DIR}"))?;
+    }
+    fs::create_dir_all(LOG_DIR).with_context(|| format!("Failed to create {LOG_DIR}"))
+}
+
+/// Deletes every log written during the run, for --nolog
+pub fn remove_log_dir() -> Result<()> {
+    fs::remove_dir_all(LOG_DIR).with_context(|| format!("Failed to remove {LOG_DIR}"))
+}
@@ -0,0 +1,216 @@
+use std::collections::HashMap;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use rayon::prelude::*;
+
+use crate::process::output_with_timeout;
+use crate::selection::Test;
+
+/// Problems found with tests before any of them are graded
+#[derive(Debug, Default)]
+pub struct Vetting {
+    /// Maps each duplicate test to the [`Test::name`] of the first test with the
+    /// same source
+    pub duplicates: HashMap<PathBuf, String>,
+    /// Maps each dodgy test to what the analyzers reported about it
+    pub dodgy: HashMap<PathBuf, String>,
+}
+
+/// Runs every analyzer in `analysis_dir` over each test and looks for tests
+/// that are copies of each other.
+///
+/// An analyzer is any executable in the directory. It is invoked as
+/// `<analyzer> <test>` and a non-zero exit marks the test as dodgy, with its
+/// stdout and stderr as the reason. Tests are named relative to `test_root`.
+pub fn vet_tests(
+    tests: &[Test],
+    test_root: &Path,
+    analysis_dir: &Path,
+    limit: Duration,
+) -> Result<Vetting> {
+    let analyzers = find_analyzers(analysis_dir)?;
+
+    let dodgy = tests
+        .par_iter()
+        .map(|test| -> Result<Option<(PathBuf, String)>> {
+            let mut reports = Vec::new();
+            for analyzer in &analyzers {
+                let output = output_with_timeout(Command::new(analyzer).arg(&test.path), limit)
+                    .with_context(|| format!("Failed to run analyzer {analyzer:?}"))?;
+                let name = analyzer.file_name().unwrap_or_default().to_string_lossy();
+
+                if output.timed_out() {
+                    reports.push(format!("{name}: timed out"));
+                } else if !output.success() {
+                    let report: Vec<_> = [&output.stdout, &output.stderr]
+                        .into_iter()
+                        .map(|bytes| String::from_utf8_lossy(bytes).trim().to_string())
+                        .filter(|text| !text.is_empty())
+                        .collect();
+                    reports.push(format!("{name}: {}", report.join("\n")));
+                }
+            }
+
+            Ok((!reports.is_empty()).then(|| (test.path.clone(), reports.join("\n"))))
+        })
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .flatten()
+        .collect();
+
+    Ok(Vetting {
+        duplicates: find_duplicates(tests, test_root)?,
+        dodgy,
+    })
+}
+
+fn find_analyzers(analysis_dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut analyzers = Vec::new();
+    for entry in fs::read_dir(analysis_dir)
+        .with_context(|| format!("Failed to read static analysis dir {analysis_dir:?}"))?
+    {
+        let path = entry?.path();
+        let executable = fs::metadata(&path)?.permissions().mode() & 0o111 != 0;
+        if path.is_file() && executable {
+            analyzers.push(path);
+        }
+    }
+    analyzers.sort();
+
+    Ok(analyzers)
+}
+
+/// Maps each test whose normalized source was already seen to the name of the
+/// first such test
+fn find_duplicates(tests: &[Test], test_root: &Path) -> Result<HashMap<PathBuf, String>> {
+    let mut tests: Vec<_> = tests.iter().collect();
+    tests.sort_by(|a, b| a.path.cmp(&b.path));
+
+    let mut seen: HashMap<String, &Test> = HashMap::new();
+    let mut duplicates = HashMap::new();
+    for test in tests {
+        let path = &test.path;
+        let source =
+            fs::read_to_string(path).with_context(|| format!("Failed to read test {path:?}"))?;
+        let normalized = normalize_source(&source);
+
+        match seen.get(&normalized) {
+            Some(original) => {
+                duplicates.insert(path.clone(), original.name(test_root));
+            }
+            None => {
+                seen.insert(normalized, test);
+            }
+        }
+    }
+
+    Ok(duplicates)
+}
+
+/// Strips comments (including the test directive) and collapses whitespace so
+/// that cosmetic edits don't hide a copied test
+fn normalize_source(source: &str) -> String {
+    let is_ident = |c: char| c.is_alphanumeric() || c == '_';
+
+    let mut normalized = String::new();
+    let mut chars = source.chars().peekable();
+    // C0 block comments nest
+    let mut comment_depth = 0;
+    let mut pending_space = false;
+
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('/', Some('*')) => {
+                chars.next();
+                comment_depth += 1;
+                pending_space = true;
+            }
+            ('*', Some('/')) if comment_depth > 0 => {
+                chars.next();
+                comment_depth -= 1;
+            }
+            _ if comment_depth > 0 => {}
+            // String and char literals are kept verbatim, comment markers and all
+            ('"' | '\'', _) => {
+                normalized.push(c);
+                while let Some(next) = chars.next() {
+                    normalized.push(next);
+                    match next {
+                        '\\' => normalized.extend(chars.next()),
+                        '\n' => break,
+                        _ if next == c => break,
+                        _ => {}
+                    }
+                }
+                pending_space = false;
+            }
+            ('/', Some('/')) => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+                pending_space = true;
+            }
+            (c, _) if c.is_whitespace() => pending_space = true,
+            (c, _) => {
+                // Whitespace only matters when it separates two identifiers
+                if pending_space && normalized.ends_with(is_ident) && is_ident(c) {
+                    normalized.push(' ');
+                }
+                normalized.push(c);
+                pending_space = false;
+            }
+        }
+    }
+
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalization_ignores_comments_and_whitespace() {
+        let original = "//test return 0\nint main() {\n  return 0;\n}\n";
+        let copy = "//test return 1\n/* copied /* nested */ */\nint   main(){ return 0; // done\n}";
+        let different = "//test return 0\nint main() { return 1; }";
+
+        assert_eq!(normalize_source(original), "int main(){return 0;}");
+        assert_eq!(normalize_source(original), normalize_source(copy));
+        assert_ne!(normalize_source(original), normalize_source(different));
+
+        let literals = "int main() { string s = \"/* \\\" // \"; char c = '\"'; return 0; }";
+        assert_eq!(
+            normalize_source(literals),
+            "int main(){string s=\"/* \\\" // \";char c='\"';return 0;}"
+        );
+    }
+
+    #[test]
+    fn duplicates_are_named_relative_to_the_test_root() {
+        let root = tempdir::TempDir::new("c0_vetting").unwrap();
+        fs::create_dir(root.path().join("c")).unwrap();
+        let tests: Vec<_> = ["c/dup1.l3", "c/dup2.l3"]
+            .into_iter()
+            .map(|name| {
+                let path = root.path().join(name);
+                fs::write(&path, "//test return 0\nint main() { return 0; }\n").unwrap();
+                Test {
+                    path,
+                    directive: Ok(crate::parser::TestResult::Ret(0)),
+                }
+            })
+            .collect();
+
+        let duplicates = find_duplicates(&tests, root.path()).unwrap();
+        assert_eq!(duplicates.len(), 1);
+        assert_eq!(duplicates[&tests[1].path], "c/dup1.l3");
+    }
+}